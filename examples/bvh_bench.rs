@@ -0,0 +1,146 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_raytracing::*;
+use std::time::Instant;
+
+const WIDTH: u32 = 500;
+const HEIGHT: u32 = 500;
+const PASSES: u32 = 4;
+/// Number of spheres in the scene timing the parallel build.
+const BUILD_SPHERES: u32 = 200_000;
+
+/// Builds the boxes and spheres of `final_scene` with every split method and compares the
+/// speed of tracing camera rays through them, then times building a large scene on 1 and on
+/// all threads.
+pub fn main() {
+    let look_from = Point3::new(478.0, 278.0, -600.0);
+    let look_at = Point3::new(278.0, 278.0, 0.0);
+    let v_up = Vec3::new(0.0, 1.0, 0.0);
+    let camera = Camera::new(&look_from, &look_at, &v_up, 40.0, 1.0, 0.0, 10.0, 0.0, 1.0);
+
+    for (name, split_method) in [
+        ("median", SplitMethod::Median),
+        ("sah", SplitMethod::Sah { max_leaf_size: 4 }),
+    ] {
+        let world = scene();
+        let now = Instant::now();
+        let bvh = BVH::from_world_with(world, 0.0, 1.0, split_method);
+        let build_time = now.elapsed().as_secs_f64();
+        let sah_cost = bvh.sah_cost();
+        let (hits, rays_per_second) = trace(&bvh, &camera);
+        println!(
+            "{:>6}: built in {:.2}ms, SAH cost {:.1}, {} hits, {:.2} Mrays/s",
+            name,
+            build_time * 1000.0,
+            sah_cost,
+            hits,
+            rays_per_second / 1e6
+        );
+
+        let now = Instant::now();
+        let bvh4 = BVH4::from_bvh(bvh);
+        let collapse_time = now.elapsed().as_secs_f64();
+        let (hits, rays_per_second) = trace(&bvh4, &camera);
+        println!(
+            "{:>6}: collapsed to 4-wide in {:.2}ms, {} hits, {:.2} Mrays/s",
+            name,
+            collapse_time * 1000.0,
+            hits,
+            rays_per_second / 1e6
+        );
+    }
+
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    for threads in [1, threads] {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let world = spheres();
+        let now = Instant::now();
+        let bvh = thread_pool.install(|| {
+            BVH::from_world_with(world, 0.0, 1.0, SplitMethod::Sah { max_leaf_size: 4 })
+        });
+        let build_time = now.elapsed().as_secs_f64();
+        println!(
+            "{} spheres on {:>2} threads: built in {:.2}ms, SAH cost {:.1}",
+            BUILD_SPHERES,
+            threads,
+            build_time * 1000.0,
+            bvh.sah_cost()
+        );
+    }
+}
+
+/// Traces the camera rays through `hittable`, returns the number of hits and the rays traced
+/// per second.
+fn trace<H: Hittable>(hittable: &H, camera: &Camera) -> (u64, f64) {
+    let mut sampler = RandomSampler::new(0);
+    let mut hits = 0u64;
+    let now = Instant::now();
+    for _ in 0..PASSES {
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                sampler.start_pixel_sample((x, y), 0);
+                let ray = camera.get_ray(
+                    (x as f32 + 0.5) / WIDTH as f32,
+                    1.0 - (y as f32 + 0.5) / HEIGHT as f32,
+                    &mut sampler,
+                );
+                if hittable.hit(&ray, 0.001, f32::INFINITY).is_some() {
+                    hits += 1;
+                }
+            }
+        }
+    }
+    let rays = (PASSES * WIDTH * HEIGHT) as f64;
+    (hits, rays / now.elapsed().as_secs_f64())
+}
+
+fn scene() -> World {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut world = World::default();
+
+    let ground = Lambertian::new(SolidTexture::from_rgb(0.48, 0.83, 0.53));
+    const BOXES_PER_SIDE: u32 = 20;
+    for i in 0..BOXES_PER_SIDE {
+        for j in 0..BOXES_PER_SIDE {
+            let w = 100.0;
+            let x0 = -1000.0 + i as f32 * w;
+            let z0 = -1000.0 + j as f32 * w;
+            let y1 = rng.gen_range(1..101) as f32;
+            world.add(Box3d::new(
+                Point3::new(x0, 0.0, z0),
+                Point3::new(x0 + w, y1, z0 + w),
+                ground,
+            ));
+        }
+    }
+
+    let white = Lambertian::new(SolidTexture::from_rgb(0.73, 0.73, 0.73));
+    let offset = Vec3::new(-100.0, 270.0, 395.0);
+    for _ in 0..1000 {
+        let center = Point3::new(
+            rng.gen_range(0.0..165.0),
+            rng.gen_range(0.0..165.0),
+            rng.gen_range(0.0..165.0),
+        );
+        world.add(Sphere::new(center + offset, 10.0, white));
+    }
+    world
+}
+
+fn spheres() -> World {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut world = World::default();
+    let white = Lambertian::new(SolidTexture::from_rgb(0.73, 0.73, 0.73));
+    for _ in 0..BUILD_SPHERES {
+        let center = Point3::new(
+            rng.gen_range(-1000.0..1000.0),
+            rng.gen_range(-1000.0..1000.0),
+            rng.gen_range(-1000.0..1000.0),
+        );
+        world.add(Sphere::new(center, 2.0, white));
+    }
+    world
+}
@@ -50,8 +50,8 @@ pub fn main() -> Result<(), String> {
         MAX_DEPTH,
         Color::new(0.0, 0.0, 0.0),
     )?;
-    renderer.render(&world, &camera, Some(&lights))?;
-    renderer.present()?;
+    renderer.set_denoiser(Some(Denoiser::default()));
+    renderer.render_progressive(&world, &camera, Some(&lights))?;
     Ok(())
 }
 
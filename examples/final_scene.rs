@@ -1,5 +1,6 @@
 use rand::Rng;
 use rust_raytracing::*;
+use std::sync::Arc;
 
 const ASPECT_RATIO: f32 = 1.0;
 const SCREEN_WIDTH: u32 = 600;
@@ -8,7 +9,7 @@ const SAMPLES_PER_PIXEL: u32 = 5;
 const MAX_DEPTH: u32 = 5;
 
 pub fn main() -> Result<(), String> {
-    let world = final_scene();
+    let world = BVH::from_world(final_scene(), 0.0, 1.0);
 
     let dummy_material = Lambertian::new(SolidTexture::from_color(
         Color::new(0.4, 0.2, 0.1),
@@ -40,17 +41,24 @@ pub fn main() -> Result<(), String> {
         MAX_DEPTH,
         Color::new(0.0, 0.0, 0.0),
     )?;
+    renderer.set_display_transform(DisplayTransform::new(0.0, Aces));
     renderer.render(&world, &camera, Some(&light))?;
     renderer.present()?;
     Ok(())
 }
 
 fn final_scene() -> World {
-    let mut boxes = World::default();
+    let mut world = World::default();
 
     let ground = Lambertian::new(SolidTexture::from_rgb(
         0.48, 0.83, 0.53,
     ));
+    // every ground box is the same unit box, scaled and moved into place
+    let unit_box = Arc::new(Box3d::new(
+        Point3::new(0.0, 0.0, 0.0),
+        Point3::new(1.0, 1.0, 1.0),
+        ground,
+    ));
 
     const BOXES_PER_SIDE: u32 = 20;
     for i in 0..BOXES_PER_SIDE {
@@ -58,23 +66,16 @@ fn final_scene() -> World {
             let w = 100.0;
             let x0 = -1000.0 + i as f32 * w;
             let z0 = -1000.0 + j as f32 * w;
-            let y0 = 0.0;
-            let x1 = x0 + w;
             let y1 = rand::thread_rng().gen_range(1..101) as f32;
-            let z1 = z0 + w;
 
-            boxes.add(Box3d::new(
-                Point3::new(x0, y0, z0),
-                Point3::new(x1, y1, z1),
-                ground,
+            world.add(Instance::new(
+                unit_box.clone(),
+                Transform::scale(Vec3::new(w, y1, w))
+                    .then(&Transform::translate(Vec3::new(x0, 0.0, z0))),
             ));
         }
     }
 
-    let mut world = World::default();
-
-    world.add(boxes);
-
     let light = DiffuseLight::new(SolidTexture::from_rgb(
         7.0, 7.0, 7.0,
     ));
@@ -147,24 +148,24 @@ fn final_scene() -> World {
         earth_material,
     ));
 
-    let pertext = Lambertian::new(NoiseTexture::new(0.1));
+    let pertext = Lambertian::new(NoiseTexture::new(0.1, 0));
     world.add(Sphere::new(Point3::new(220.0, 280.0, 300.0), 80.0, pertext));
 
-    // TODO rethink Translation and Rotation
     let mut boxes2 = World::default();
     let white = Lambertian::new(SolidTexture::from_rgb(
         0.73, 0.73, 0.73,
     ));
     for _ in 0..1000 {
         boxes2.add(Sphere::new(
-            Point3::random(0.0, 165.0),
+            Point3::random(&mut rand::thread_rng(), 0.0, 165.0),
             10.0,
             white,
         ));
     }
-    world.add(Translate::new(
-        Rotate::new(boxes2, 15.0),
-        Vec3::new(-100.0, 270.0, 395.0),
+    world.add(Instance::new(
+        Arc::new(BVH::from_world(boxes2, 0.0, 1.0)),
+        Transform::rotate(Vec3::new(0.0, 1.0, 0.0), 15.0)
+            .then(&Transform::translate(Vec3::new(-100.0, 270.0, 395.0))),
     ));
 
     world
@@ -65,8 +65,9 @@ fn scene() -> World {
             );
             if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 if choose_mat < 0.8 {
-                    let material_lambertian =
-                        Lambertian::new(SolidTexture::from_color(Color::random(0.0, 0.5)));
+                    let material_lambertian = Lambertian::new(SolidTexture::from_color(
+                        Color::random(&mut rng, 0.0, 0.5),
+                    ));
                     let mut rng = rand::thread_rng();
                     let uniform = rand::distributions::Uniform::new(0.0, 0.5);
                     let center2 = center + Vec3::new(0.0, uniform.sample(&mut rng), 0.0);
@@ -80,7 +81,7 @@ fn scene() -> World {
                     ));
                 } else if choose_mat < 0.95 {
                     let material_metal = Metal::new(
-                        SolidTexture::from_color(Color::random(0.5, 1.0)),
+                        SolidTexture::from_color(Color::random(&mut rng, 0.5, 1.0)),
                         uniform.sample(&mut rng),
                     );
                     world.add(Sphere::new(center, 0.2, material_metal));
@@ -12,42 +12,61 @@ impl AABB {
         Self { minimum, maximum }
     }
 
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
-        Self::hit_check(
-            self.minimum.x,
-            self.maximum.x,
-            ray.direction.x,
-            ray.origin.x,
-            t_min,
-            t_max,
-        ) || Self::hit_check(
-            self.minimum.y,
-            self.maximum.y,
-            ray.direction.y,
-            ray.origin.y,
-            t_min,
-            t_max,
-        ) || Self::hit_check(
-            self.minimum.z,
-            self.maximum.z,
-            ray.direction.z,
-            ray.origin.z,
-            t_min,
-            t_max,
+    /// Slab test, returns the interval of `[t_min, t_max]` during which the ray is inside the
+    /// box. Rays touching the box only on its boundary hit it, NaNs in the ray miss it.
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        if t_min.is_nan() || t_max.is_nan() {
+            return None;
+        }
+        let (mut entry, mut exit) = (t_min, t_max);
+        for axis in 0..3 {
+            let origin = ray.origin[axis];
+            let direction = ray.direction[axis];
+            let (min, max) = (self.minimum[axis], self.maximum[axis]);
+            let inverse = 1.0 / direction;
+            if inverse.is_infinite() {
+                // parallel to the slab (or so close to it that `1 / direction` overflows), the
+                // ray is either always or never between its planes
+                if !(min <= origin && origin <= max) {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (min - origin) * inverse;
+            let t1 = (max - origin) * inverse;
+            let (near, far) = if inverse < 0.0 { (t1, t0) } else { (t0, t1) };
+            if near.is_nan() || far.is_nan() {
+                return None;
+            }
+            entry = entry.max(near);
+            exit = exit.min(far);
+        }
+        if entry <= exit {
+            Some((entry, exit))
+        } else {
+            None
+        }
+    }
+
+    /// Box containing nothing, the identity of `surrounding_box`.
+    pub fn empty() -> Self {
+        Self::new(
+            Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
         )
     }
 
-    #[inline]
-    fn hit_check(min: f32, max: f32, dir: f32, orig: f32, mut t_min: f32, mut t_max: f32) -> bool {
-        let inv = 1.0 / dir;
-        let mut t0 = (min - orig) * inv;
-        let mut t1 = (max - orig) * inv;
-        if inv < 0.0 {
-            std::mem::swap(&mut t0, &mut t1);
+    /// Grows the box to contain `point`.
+    pub fn include(&self, point: &Point3) -> Self {
+        Self::surrounding_box(*self, Self::new(*point, *point))
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.maximum - self.minimum;
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 {
+            return 0.0;
         }
-        t_min = t0.min(t_min);
-        t_max = t1.max(t_max);
-        t_max > t_min
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
     }
 
     pub fn surrounding_box(box0: AABB, box1: AABB) -> AABB {
@@ -64,3 +83,115 @@ impl AABB {
         AABB::new(small, big)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unit_box() -> AABB {
+        AABB::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn aabb_slab_test() {
+        let aabb = unit_box();
+        let ray = |origin: (f32, f32, f32), direction: (f32, f32, f32)| {
+            Ray::new(
+                Point3::new(origin.0, origin.1, origin.2),
+                Point3::new(direction.0, direction.1, direction.2),
+                0.0,
+            )
+        };
+
+        // diagonal ray through the box
+        let (entry, exit) = aabb
+            .hit(
+                &ray((-1.0, -1.0, -1.0), (1.0, 1.0, 1.0)),
+                0.0,
+                f32::INFINITY,
+            )
+            .unwrap();
+        assert_eq!((entry, exit), (1.0, 2.0));
+        // the interval is clamped to the queried one
+        assert_eq!(
+            aabb.hit(&ray((-1.0, -1.0, -1.0), (1.0, 1.0, 1.0)), 1.5, 10.0),
+            Some((1.5, 2.0))
+        );
+        assert!(aabb
+            .hit(&ray((-1.0, -1.0, -1.0), (1.0, 1.0, 1.0)), 0.0, 0.5)
+            .is_none());
+        // box behind the ray
+        assert!(aabb
+            .hit(&ray((2.0, 0.5, 0.5), (1.0, 0.0, 0.0)), 0.0, f32::INFINITY)
+            .is_none());
+        // missing diagonally although every single slab is crossed
+        assert!(aabb
+            .hit(&ray((-1.0, 0.0, 0.5), (1.0, 3.0, 0.0)), 0.0, f32::INFINITY)
+            .is_none());
+    }
+
+    #[test]
+    fn aabb_inside_box() {
+        let ray = Ray::new(Point3::new(0.5, 0.5, 0.5), Point3::new(0.0, -2.0, 1.0), 0.0);
+        assert_eq!(unit_box().hit(&ray, 0.0, f32::INFINITY), Some((0.0, 0.25)));
+    }
+
+    #[test]
+    fn aabb_axis_parallel_and_grazing() {
+        let aabb = unit_box();
+        let x = Point3::new(1.0, 0.0, 0.0);
+        // parallel to x inside the y and z slabs
+        let ray = Ray::new(Point3::new(-1.0, 0.5, 0.5), x, 0.0);
+        assert_eq!(aabb.hit(&ray, 0.0, f32::INFINITY), Some((1.0, 2.0)));
+        // parallel to x outside the y slab
+        let ray = Ray::new(Point3::new(-1.0, 1.5, 0.5), x, 0.0);
+        assert!(aabb.hit(&ray, 0.0, f32::INFINITY).is_none());
+        // sliding along a face and along an edge
+        let ray = Ray::new(Point3::new(-1.0, 1.0, 0.5), x, 0.0);
+        assert_eq!(aabb.hit(&ray, 0.0, f32::INFINITY), Some((1.0, 2.0)));
+        let ray = Ray::new(Point3::new(-1.0, 0.0, 0.0), x, 0.0);
+        assert_eq!(aabb.hit(&ray, 0.0, f32::INFINITY), Some((1.0, 2.0)));
+        // touching a corner only
+        let ray = Ray::new(
+            Point3::new(-1.0, 1.0, -1.0),
+            Point3::new(1.0, -1.0, 1.0),
+            0.0,
+        );
+        assert_eq!(aabb.hit(&ray, 0.0, f32::INFINITY), Some((1.0, 1.0)));
+        // flat box
+        let flat = AABB::new(Point3::new(0.0, 0.0, 1.0), Point3::new(1.0, 1.0, 1.0));
+        let ray = Ray::new(Point3::new(0.5, 0.5, 0.0), Point3::new(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(flat.hit(&ray, 0.0, f32::INFINITY), Some((1.0, 1.0)));
+        // subnormal direction components starting on a slab plane
+        for &y in &[0.0, 1.0] {
+            let ray = Ray::new(Point3::new(-1.0, y, 0.5), Point3::new(1.0, 1e-40, 0.0), 0.0);
+            assert_eq!(aabb.hit(&ray, 0.0, f32::INFINITY), Some((1.0, 2.0)));
+        }
+        let ray = Ray::new(
+            Point3::new(-1.0, 1.5, 0.5),
+            Point3::new(1.0, -1e-40, 0.0),
+            0.0,
+        );
+        assert!(aabb.hit(&ray, 0.0, f32::INFINITY).is_none());
+    }
+
+    #[test]
+    fn aabb_degenerate_rays() {
+        let aabb = unit_box();
+        let nan = Ray::new(
+            Point3::new(0.5, 0.5, 0.5),
+            Point3::new(f32::NAN, 1.0, 0.0),
+            0.0,
+        );
+        assert!(aabb.hit(&nan, 0.0, f32::INFINITY).is_none());
+        let nan = Ray::new(
+            Point3::new(f32::NAN, 0.5, 0.5),
+            Point3::new(1.0, 0.0, 0.0),
+            0.0,
+        );
+        assert!(aabb.hit(&nan, 0.0, f32::INFINITY).is_none());
+        let ray = Ray::new(Point3::new(0.5, 0.5, 0.5), Point3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(AABB::empty().hit(&ray, 0.0, f32::INFINITY).is_none());
+        assert!(aabb.hit(&ray, f32::NAN, f32::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,66 @@
+use crate::vec3::Color;
+
+/// Settings of adaptive sampling. Every pixel first gets `min_samples` samples, then only
+/// pixels whose estimated error is above `threshold` receive more.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveSampling {
+    /// Relative standard error of the pixel mean below which a pixel counts as converged.
+    pub threshold: f32,
+    /// Samples taken in every pixel before its error is estimated, at least 2.
+    pub min_samples: u32,
+}
+
+impl AdaptiveSampling {
+    /// Pixels darker than this are compared against it instead of their own luminance,
+    /// otherwise almost black pixels would never converge.
+    const MIN_LUMINANCE: f32 = 0.05;
+
+    pub fn new(threshold: f32, min_samples: u32) -> Self {
+        Self {
+            threshold,
+            min_samples: min_samples.max(2),
+        }
+    }
+
+    /// Estimates the relative standard error of the mean luminance of a pixel from the
+    /// `sum` of the luminance of its `samples` and the sum of their `squares`.
+    pub fn relative_error(sum: f32, squares: f32, samples: u32) -> f32 {
+        if samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = samples as f32;
+        let mean = sum / n;
+        let variance = ((squares / n - mean * mean) * n / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() / mean.max(Self::MIN_LUMINANCE)
+    }
+
+    /// Whether a pixel with the given sample statistics needs more samples.
+    pub fn needs_samples(&self, sum: f32, squares: f32, samples: u32) -> bool {
+        samples < self.min_samples || Self::relative_error(sum, squares, samples) > self.threshold
+    }
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        Self::new(0.02, 4)
+    }
+}
+
+/// Maps the number of samples spent in every pixel to colors going from blue (fewest)
+/// through green to red (most samples).
+pub fn heat_map(sample_counts: &[u32]) -> Vec<Color> {
+    let min = sample_counts.iter().copied().min().unwrap_or(0);
+    let max = sample_counts.iter().copied().max().unwrap_or(0);
+    let range = (max - min).max(1) as f32;
+    sample_counts
+        .iter()
+        .map(|count| {
+            let t = (count - min) as f32 / range;
+            if t < 0.5 {
+                Color::new(0.0, 2.0 * t, 1.0 - 2.0 * t)
+            } else {
+                Color::new(2.0 * t - 1.0, 2.0 - 2.0 * t, 0.0)
+            }
+        })
+        .collect()
+}
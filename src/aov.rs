@@ -0,0 +1,156 @@
+use crate::camera::Camera;
+use crate::hittable::HitRecord;
+use crate::sampler::hash;
+use crate::tonemap::srgb_oetf;
+use crate::vec3::{Color, Vec3};
+
+/// Arbitrary output variables, values recorded at the first hit of the camera rays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Distance from the camera along its viewing direction, infinity where nothing was hit.
+    Depth,
+    /// World space shading normal.
+    Normal,
+    /// Surface color of the material, the background color where nothing was hit.
+    Albedo,
+    /// Texture coordinates stored in the red and green channels.
+    Uv,
+    /// Id of the hit object (see [`crate::World::object_id`]), `0` where nothing was hit.
+    ObjectId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 5] = [Aov::Depth, Aov::Normal, Aov::Albedo, Aov::Uv, Aov::ObjectId];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Uv => "uv",
+            Aov::ObjectId => "object_id",
+        }
+    }
+}
+
+/// First hit values of camera samples summed over all samples of a pixel.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AovSample {
+    pub depth: f32,
+    pub normal: Vec3,
+    pub albedo: Color,
+    pub u: f32,
+    pub v: f32,
+    /// Object id of the first sample that hit anything.
+    pub object_id: u32,
+    /// Number of samples that hit anything.
+    pub hits: u32,
+    /// Number of all samples.
+    pub samples: u32,
+}
+
+impl AovSample {
+    pub fn from_hit(hit: &HitRecord, camera: &Camera) -> Self {
+        Self {
+            depth: (hit.point - camera.origin).dot(&-camera.w),
+            normal: hit.normal,
+            albedo: hit
+                .material
+                .map_or(Color::default(), |material| material.albedo(hit)),
+            u: hit.u,
+            v: hit.v,
+            object_id: hit.object_id,
+            hits: 1,
+            samples: 1,
+        }
+    }
+
+    pub fn from_miss(background: &Color) -> Self {
+        Self {
+            albedo: *background,
+            samples: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, other: &AovSample) {
+        self.depth += other.depth;
+        self.normal += other.normal;
+        self.albedo += other.albedo;
+        self.u += other.u;
+        self.v += other.v;
+        if self.object_id == 0 {
+            self.object_id = other.object_id;
+        }
+        self.hits += other.hits;
+        self.samples += other.samples;
+    }
+
+    /// Averaged value of the `aov`.
+    pub fn resolve(&self, aov: Aov) -> Color {
+        match aov {
+            Aov::Depth => {
+                let depth = if self.hits == 0 {
+                    f32::INFINITY
+                } else {
+                    self.depth / self.hits as f32
+                };
+                Color::new(depth, depth, depth)
+            }
+            Aov::Normal => {
+                if self.normal.length_squared() > 0.0 {
+                    self.normal.unit()
+                } else {
+                    Vec3::default()
+                }
+            }
+            Aov::Albedo => self.albedo / self.samples.max(1) as f32,
+            Aov::Uv => {
+                let hits = self.hits.max(1) as f32;
+                Color::new(self.u / hits, self.v / hits, 0.0)
+            }
+            Aov::ObjectId => {
+                let id = self.object_id as f32;
+                Color::new(id, id, id)
+            }
+        }
+    }
+}
+
+/// Turns resolved `aov` values into 8-bit colors for viewing. Depth is normalized by the
+/// farthest depth in `values`, normals are remapped from [-1, 1] and object ids get random
+/// colors.
+pub fn visualize_aov(aov: Aov, values: &[Color]) -> Vec<u8> {
+    let encode = |value: f32| (255.0 * value.clamp(0.0, 1.0) + 0.5) as u8;
+    let max_depth = values
+        .iter()
+        .map(|value| value.x)
+        .filter(|depth| depth.is_finite())
+        .fold(0.0_f32, f32::max);
+    values
+        .iter()
+        .flat_map(|value| {
+            let color = match aov {
+                Aov::Depth if value.x.is_finite() && max_depth > 0.0 => *value / max_depth,
+                Aov::Depth => Color::new(1.0, 1.0, 1.0),
+                Aov::Normal => (*value + Color::new(1.0, 1.0, 1.0)) * 0.5,
+                Aov::Albedo => Color::new(
+                    srgb_oetf(value.x.clamp(0.0, 1.0)),
+                    srgb_oetf(value.y.clamp(0.0, 1.0)),
+                    srgb_oetf(value.z.clamp(0.0, 1.0)),
+                ),
+                Aov::Uv => *value,
+                Aov::ObjectId if value.x == 0.0 => Color::default(),
+                Aov::ObjectId => {
+                    let h = hash(&[value.x as u64]);
+                    Color::new(
+                        (h & 0xff) as f32 / 255.0,
+                        ((h >> 8) & 0xff) as f32 / 255.0,
+                        ((h >> 16) & 0xff) as f32 / 255.0,
+                    )
+                }
+            };
+            [encode(color.x), encode(color.y), encode(color.z)]
+        })
+        .collect()
+}
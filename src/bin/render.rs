@@ -0,0 +1,240 @@
+use rust_raytracing::*;
+
+const USAGE: &str = "Usage: render <scene.toml> [options]
+
+Options:
+    -o, --output <path>     image to write, the format is picked by the extension [default: render.png]
+    -W, --width <pixels>    image width, overrides the scene file
+    -H, --height <pixels>   image height, overrides the scene file
+    -s, --spp <samples>     samples per pixel, overrides the scene file
+    -d, --max-depth <n>     maximum number of bounces, overrides the scene file
+        --seed <n>          seed of the sampler, overrides the scene file
+        --sampler <name>    random, stratified, halton or sobol, overrides the scene file
+    -t, --threads <n>       number of render threads, 0 uses all cores [default: 0]
+        --denoise           denoise the image
+    -p, --preview           show the image in a window while rendering
+    -h, --help              print this message";
+
+#[derive(Default)]
+struct Options {
+    scene: String,
+    output: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    samples_per_pixel: Option<u32>,
+    max_depth: Option<u32>,
+    seed: Option<u64>,
+    sampler: Option<SamplerType>,
+    threads: usize,
+    denoise: bool,
+    preview: bool,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut options = Options {
+            output: "render.png".to_string(),
+            ..Default::default()
+        };
+        let mut scene = None;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for `{}`", arg))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-o" | "--output" => options.output = value()?,
+                "-W" | "--width" => options.width = Some(number(&arg, &value()?)?),
+                "-H" | "--height" => options.height = Some(number(&arg, &value()?)?),
+                "-s" | "--spp" => options.samples_per_pixel = Some(number(&arg, &value()?)?),
+                "-d" | "--max-depth" => options.max_depth = Some(number(&arg, &value()?)?),
+                "--seed" => options.seed = Some(number(&arg, &value()?)?),
+                "--sampler" => {
+                    options.sampler = Some(match value()?.as_str() {
+                        "random" => SamplerType::Random,
+                        "stratified" => SamplerType::Stratified,
+                        "halton" => SamplerType::Halton,
+                        "sobol" => SamplerType::Sobol,
+                        other => return Err(format!("unknown sampler `{}`", other)),
+                    })
+                }
+                "-t" | "--threads" => options.threads = number(&arg, &value()?)?,
+                "--denoise" => options.denoise = true,
+                "-p" | "--preview" => options.preview = true,
+                _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+                _ if scene.is_none() => scene = Some(arg),
+                _ => return Err(format!("unexpected argument `{}`", arg)),
+            }
+        }
+        options.scene = scene.ok_or("missing scene file")?;
+        Ok(Some(options))
+    }
+
+    fn apply(&self, settings: &mut RenderSettings) {
+        if let Some(width) = self.width {
+            settings.width = width;
+        }
+        if let Some(height) = self.height {
+            settings.height = height;
+        }
+        if let Some(samples_per_pixel) = self.samples_per_pixel {
+            settings.samples_per_pixel = samples_per_pixel;
+        }
+        if let Some(max_depth) = self.max_depth {
+            settings.max_depth = max_depth;
+        }
+        if let Some(seed) = self.seed {
+            settings.seed = seed;
+        }
+        if let Some(sampler) = self.sampler {
+            settings.sampler = sampler;
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{}` for `{}`", value, option))
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let options = match Options::parse(std::env::args().skip(1))
+        .map_err(|e| format!("{}\n\n{}", e, USAGE))?
+    {
+        Some(options) => options,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    // the scene is built on the same threads as it is rendered
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let now = std::time::Instant::now();
+    let scene = thread_pool
+        .install(|| Scene::load_with(&options.scene, |settings| options.apply(settings)))?;
+    let load_time = now.elapsed();
+
+    let mut renderer = scene.renderer()?;
+    renderer.set_thread_pool(thread_pool);
+    if options.denoise {
+        renderer.set_denoiser(Some(Denoiser::default()));
+    }
+
+    let settings = &scene.settings;
+    println!(
+        "Rendering {} at {}x{}, {} spp, max depth {}, seed {}, {} threads",
+        options.scene,
+        settings.width,
+        settings.height,
+        settings.samples_per_pixel,
+        settings.max_depth,
+        settings.seed,
+        renderer.threads()
+    );
+    if options.preview {
+        preview(&scene, &mut renderer)?;
+    } else {
+        scene.render(&mut renderer)?;
+    }
+    renderer.save(&options.output)?;
+
+    let stats = renderer.stats();
+    println!("Scene loaded in {:.3}s", load_time.as_secs_f64());
+    println!("Rendered in {:.3}s", stats.render_time.as_secs_f64());
+    println!(
+        "Samples: {}, rays: {} ({:.2} rays per sample, {:.2} Mrays/s)",
+        stats.samples,
+        stats.rays,
+        stats.rays as f64 / stats.samples.max(1) as f64,
+        stats.rays_per_second() / 1e6
+    );
+    println!("Saved {}", options.output);
+    Ok(())
+}
+
+#[cfg(feature = "sdl")]
+fn preview(scene: &Scene, renderer: &mut Renderer) -> Result<(), String> {
+    renderer.render_progressive(&scene.world, &scene.camera, scene.lights.as_ref())
+}
+
+#[cfg(not(feature = "sdl"))]
+fn preview(_scene: &Scene, _renderer: &mut Renderer) -> Result<(), String> {
+    Err("the preview window needs the `sdl` feature".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Option<Options>, String> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parse_overrides() {
+        let options = parse("scene.toml -W 320 -H 240 -s 8 -d 4 --seed 9 --sampler sobol -t 2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.scene, "scene.toml");
+        assert_eq!(options.output, "render.png");
+        assert_eq!(options.threads, 2);
+        assert!(!options.denoise && !options.preview);
+
+        let mut settings = RenderSettings {
+            width: 100,
+            height: 100,
+            samples_per_pixel: 1,
+            max_depth: 50,
+            background: Color::default(),
+            seed: 0,
+            sampler: SamplerType::Random,
+            bvh: true,
+        };
+        options.apply(&mut settings);
+        assert_eq!((settings.width, settings.height), (320, 240));
+        assert_eq!((settings.samples_per_pixel, settings.max_depth), (8, 4));
+        assert_eq!((settings.seed, settings.sampler), (9, SamplerType::Sobol));
+
+        // options that are not given keep the values of the scene file
+        let options = parse("--denoise -o out.exr scene.toml -p")
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.output, "out.exr");
+        assert!(options.denoise && options.preview);
+        options.apply(&mut settings);
+        assert_eq!((settings.width, settings.seed), (320, 9));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |args| parse(args).err().unwrap();
+        assert_eq!(error("scene.toml --sampler owen"), "unknown sampler `owen`");
+        assert_eq!(error("scene.toml -W"), "missing value for `-W`");
+        assert_eq!(
+            error("scene.toml --spp many"),
+            "invalid value `many` for `--spp`"
+        );
+        assert_eq!(error("scene.toml --fast"), "unknown option `--fast`");
+        assert_eq!(error("a.toml b.toml"), "unexpected argument `b.toml`");
+        assert_eq!(error("-W 10"), "missing scene file");
+    }
+
+    #[test]
+    fn parse_help() {
+        assert!(parse("--help").unwrap().is_none());
+        assert!(parse("scene.toml -h -W").unwrap().is_none());
+    }
+}
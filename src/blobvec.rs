@@ -1,17 +1,28 @@
-use std::alloc::Layout;
+use std::alloc::{self, Layout};
+use std::ptr::NonNull;
 
+/// Objects of one type stored without knowing the type, in memory allocated with the
+/// alignment of the type so they can be referenced in place.
 pub struct BlobVec {
     layout: Layout,
     len: usize,
-    data: Vec<u8>,
+    capacity: usize,
+    data: NonNull<u8>,
 }
 
+// The objects are only reached through `World`, which stores `Hittable`s shared between
+// render threads, like the `Vec<u8>` the objects used to be stored in.
+unsafe impl Send for BlobVec {}
+unsafe impl Sync for BlobVec {}
+
 impl BlobVec {
     pub fn new(layout: Layout) -> Self {
         Self {
             layout,
             len: 0,
-            data: Vec::new(),
+            // zero sized objects never need memory
+            capacity: if layout.size() == 0 { usize::MAX } else { 0 },
+            data: Self::dangling(layout),
         }
     }
 
@@ -25,36 +36,69 @@ impl BlobVec {
         self.len == 0
     }
 
+    /// Well aligned pointer for an empty allocation.
+    fn dangling(layout: Layout) -> NonNull<u8> {
+        NonNull::new(layout.align() as *mut u8).expect("alignments are not zero")
+    }
+
+    fn array_layout(&self, capacity: usize) -> Layout {
+        let size = self.layout.size().checked_mul(capacity);
+        size.and_then(|size| Layout::from_size_align(size, self.layout.align()).ok())
+            .expect("capacity overflow")
+    }
+
+    /// Makes room for one more object.
+    fn reserve_one(&mut self) {
+        if self.len < self.capacity {
+            return;
+        }
+        let capacity = (self.capacity * 2).max(4);
+        let layout = self.array_layout(capacity);
+        let data = unsafe {
+            if self.capacity == 0 {
+                alloc::alloc(layout)
+            } else {
+                alloc::realloc(
+                    self.data.as_ptr(),
+                    self.array_layout(self.capacity),
+                    layout.size(),
+                )
+            }
+        };
+        self.data = NonNull::new(data).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        self.capacity = capacity;
+    }
+
     /// # Safety
     /// The type T should be the type that is stored inside the [`BlobVec`]
     #[inline]
     pub unsafe fn add<T>(&mut self, object: T) {
-        let ptr: &u8 = std::mem::transmute(&object);
-        let slice = std::slice::from_raw_parts(ptr, self.layout.size());
-        self.data.extend_from_slice(slice);
+        debug_assert_eq!(self.layout, Layout::new::<T>());
+        self.reserve_one();
+        let ptr = self.data.as_ptr().add(self.len * self.layout.size()) as *mut T;
+        ptr.write(object);
         self.len += 1;
-        std::mem::forget(object);
     }
 
     /// # Safety
     /// The index should be in range 0 to blobvec.len()
     #[inline]
     pub unsafe fn get(&self, index: usize) -> &() {
-        let data_index = index * self.layout.size();
-        std::mem::transmute(&self.data[data_index])
+        debug_assert!(index < self.len);
+        &*(self.data.as_ptr().add(index * self.layout.size()) as *const ())
     }
 
     /// # Safety
     /// The index should be in range 0 to blobvec.len()
     #[inline]
     pub unsafe fn get_mut(&mut self, index: usize) -> &mut () {
-        let data_index = index * self.layout.size();
-        std::mem::transmute(&mut self.data[data_index])
+        debug_assert!(index < self.len);
+        &mut *(self.data.as_ptr().add(index * self.layout.size()) as *mut ())
     }
 
     #[inline]
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
-        self.data.as_mut_ptr()
+        self.data.as_ptr()
     }
 
     /// # Safety
@@ -65,8 +109,7 @@ impl BlobVec {
             self.layout == Layout::new::<T>(),
             "casting to type with different layout"
         );
-        let ptr: *const T = std::mem::transmute(self.data.as_ptr());
-        std::slice::from_raw_parts(ptr, self.len)
+        std::slice::from_raw_parts(self.data.as_ptr() as *const T, self.len)
     }
 
     /// # Safety
@@ -77,8 +120,16 @@ impl BlobVec {
             self.layout == Layout::new::<T>(),
             "casting to type with different layout"
         );
-        let ptr: *mut T = std::mem::transmute(self.data.as_ptr());
-        std::slice::from_raw_parts_mut(ptr, self.len)
+        std::slice::from_raw_parts_mut(self.data.as_ptr() as *mut T, self.len)
+    }
+}
+
+impl Drop for BlobVec {
+    /// Frees the memory, the objects themselves are not dropped.
+    fn drop(&mut self) {
+        if self.layout.size() != 0 && self.capacity != 0 {
+            unsafe { alloc::dealloc(self.data.as_ptr(), self.array_layout(self.capacity)) };
+        }
     }
 }
 
@@ -86,13 +137,17 @@ impl BlobVec {
 mod test {
     use super::*;
 
+    fn bytes(blob: &BlobVec) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(blob.data.as_ptr(), blob.len * blob.layout.size()) }
+    }
+
     #[test]
     fn blob_new() {
         let layout = Layout::new::<u32>();
         let blob = BlobVec::new(layout);
         assert_eq!(blob.layout, Layout::new::<u32>());
         assert_eq!(blob.len, 0);
-        assert_eq!(blob.data, []);
+        assert_eq!(bytes(&blob), [0u8; 0]);
     }
 
     #[test]
@@ -105,14 +160,14 @@ mod test {
 
         assert_eq!(blob.layout, Layout::new::<u32>());
         assert_eq!(blob.len, 1);
-        assert_eq!(blob.data, [0, 0, 0, 0]);
+        assert_eq!(bytes(&blob), [0, 0, 0, 0]);
 
         let val: u32 = 32;
         unsafe { blob.add(val) };
 
         assert_eq!(blob.layout, Layout::new::<u32>());
         assert_eq!(blob.len, 2);
-        assert_eq!(blob.data, [0, 0, 0, 0, 32, 0, 0, 0]);
+        assert_eq!(bytes(&blob), [0, 0, 0, 0, 32, 0, 0, 0]);
     }
 
     #[test]
@@ -126,17 +181,17 @@ mod test {
         let ptr = unsafe { blob.get(0) };
         let ptr: *const u8 = ptr as *const () as *const u8;
 
-        assert_eq!(ptr, blob.data.as_ptr());
+        assert_eq!(ptr, bytes(&blob).as_ptr());
 
         let val: u32 = 32;
         unsafe { blob.add(val) };
 
         let ptr = unsafe { blob.get(0) };
         let ptr: *const u8 = ptr as *const () as *const u8;
-        assert_eq!(ptr, blob.data.as_ptr());
+        assert_eq!(ptr, bytes(&blob).as_ptr());
         let ptr = unsafe { blob.get(1) };
         let ptr: *const u8 = ptr as *const () as *const u8;
-        assert_eq!(ptr, &blob.data[4] as *const u8);
+        assert_eq!(ptr, &bytes(&blob)[4] as *const u8);
     }
 
     #[test]
@@ -156,4 +211,26 @@ mod test {
 
         assert_eq!(slice, &[0, 32]);
     }
+
+    #[test]
+    fn blob_aligned() {
+        #[repr(align(32))]
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        struct Aligned(u8);
+
+        let mut blob = BlobVec::new(Layout::new::<Aligned>());
+        for i in 0..10 {
+            unsafe { blob.add(Aligned(i)) };
+            assert_eq!(
+                unsafe { blob.get(i as usize) } as *const () as usize % 32,
+                0
+            );
+        }
+        let slice = unsafe { blob.as_slice::<Aligned>() };
+        assert_eq!(slice[9], Aligned(9));
+
+        let mut empty = BlobVec::new(Layout::new::<()>());
+        unsafe { empty.add(()) };
+        assert_eq!(unsafe { empty.as_slice::<()>() }.len(), 1);
+    }
 }
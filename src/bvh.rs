@@ -1,157 +1,1016 @@
 use crate::aabb::AABB;
 use crate::hittable::{HitRecord, Hittable};
 use crate::ray::Ray;
-use crate::world::{World, WorldIndex};
+use crate::sampler::Sampler;
+use crate::vec3::{Point3, Vec3};
+use crate::world::World;
+use rayon::prelude::*;
 use std::cmp::Ordering;
 
-enum BVHNIndex {
-    Node(usize),
-    WorldIndex((WorldIndex, AABB)),
+/// Node of the flattened tree. Nodes are stored depth first, so the first child of an
+/// interior node directly follows it and its second child follows the first child's subtree.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LinearNode {
+    pub(crate) minimum: Point3,
+    pub(crate) maximum: Point3,
+    /// Interior nodes: index of the node after this subtree. Leaves: index of the first object.
+    pub(crate) offset: u32,
+    /// Number of objects of a leaf, `0` for interior nodes.
+    pub(crate) count: u16,
+    /// Axis the objects of an interior node were split along, picks the child visited first.
+    pub(crate) axis: u8,
+    _padding: u8,
 }
 
-struct BVHNode2 {
-    left: BVHNIndex,
-    right: BVHNIndex,
-    aabb: AABB,
+impl LinearNode {
+    fn new(aabb: AABB) -> Self {
+        Self {
+            minimum: aabb.minimum,
+            maximum: aabb.maximum,
+            offset: 0,
+            count: 0,
+            axis: 0,
+            _padding: 0,
+        }
+    }
+
+    pub(crate) fn aabb(&self) -> AABB {
+        AABB::new(self.minimum, self.maximum)
+    }
+
+    fn set_aabb(&mut self, aabb: AABB) {
+        self.minimum = aabb.minimum;
+        self.maximum = aabb.maximum;
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    /// Index of the node visited after this subtree.
+    pub(crate) fn skip(&self, index: usize) -> usize {
+        if self.is_leaf() {
+            index + 1
+        } else {
+            self.offset as usize
+        }
+    }
+}
+
+/// How `BVH::from_world_with` splits the objects of a node between its children.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitMethod {
+    /// Sorts the objects by the minimum corner along a round-robin axis and splits them at
+    /// the median, every leaf holds a single object.
+    Median,
+    /// Binned surface area heuristic: picks the axis and split position with the lowest
+    /// expected cost and stops splitting when a leaf of at most `max_leaf_size` objects is
+    /// cheaper.
+    Sah { max_leaf_size: usize },
 }
 
-pub struct BVH {
-    world: World,
-    nodes: Vec<BVHNode2>,
+impl Default for SplitMethod {
+    fn default() -> Self {
+        Self::Sah { max_leaf_size: 4 }
+    }
+}
+
+/// Collection of objects a `BVH` can be built over, addressed by an index.
+pub trait Primitives {
+    type Index: Copy + Send + Sync;
+
+    /// Indices of all objects with the boxes containing them during `[time0, time1]`.
+    fn volumes(&self, time0: f32, time1: f32) -> Vec<(Self::Index, AABB)>;
+    fn primitive_bounding_box(&self, index: &Self::Index, time0: f32, time1: f32) -> AABB;
+    fn hit_primitive(
+        &self,
+        index: &Self::Index,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord<'_>>;
+    fn primitive_pdf_value(&self, index: &Self::Index, origin: &Point3, direction: &Vec3) -> f32;
+    fn primitive_random(
+        &self,
+        index: &Self::Index,
+        origin: &Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3;
 }
 
-impl BVH {
+/// Any list of objects, including `Box<dyn Hittable + Send + Sync>` and the triangles of a mesh.
+impl<T: Hittable> Primitives for Vec<T> {
+    type Index = usize;
+
+    fn volumes(&self, time0: f32, time1: f32) -> Vec<(usize, AABB)> {
+        self.iter()
+            .map(|object| object.bounding_box(time0, time1))
+            .enumerate()
+            .collect()
+    }
+
+    fn primitive_bounding_box(&self, index: &usize, time0: f32, time1: f32) -> AABB {
+        self[*index].bounding_box(time0, time1)
+    }
+
+    fn hit_primitive(
+        &self,
+        index: &usize,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord<'_>> {
+        self[*index].hit(ray, t_min, t_max)
+    }
+
+    fn primitive_pdf_value(&self, index: &usize, origin: &Point3, direction: &Vec3) -> f32 {
+        self[*index].pdf_value(origin, direction)
+    }
+
+    fn primitive_random(&self, index: &usize, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self[*index].random(origin, sampler)
+    }
+}
+
+pub struct BVH<P: Primitives = World> {
+    pub(crate) primitives: P,
+    pub(crate) objects: Vec<(P::Index, AABB)>,
+    pub(crate) nodes: Vec<LinearNode>,
+    /// Index of the parent of every node, lets the traversal go up without a stack.
+    parents: Vec<u32>,
+    split_method: SplitMethod,
+    /// SAH cost right after the last build.
+    build_cost: f32,
+}
+
+/// Cost of traversing a node relative to intersecting an object.
+const TRAVERSAL_COST: f32 = 0.125;
+const SAH_BINS: usize = 12;
+/// Depth below which the SAH builder falls back to median splits, which bounds the depth of
+/// the tree by `MAX_SAH_DEPTH` plus the log2 of the object count.
+const MAX_SAH_DEPTH: usize = 32;
+/// Most objects in a leaf, the object count of a node has to fit in 16 bits.
+const MAX_LEAF_SIZE: usize = u16::MAX as usize;
+/// Nodes with at least this many objects build their children in parallel.
+const PARALLEL_BUILD_SIZE: usize = 4096;
+/// Nodes with at least twice this many objects compute their bounds and bins in parallel, in
+/// chunks of this size.
+const PARALLEL_CHUNK_SIZE: usize = 16384;
+
+/// Object counts and boxes of the bins along each axis.
+type Bins = [[(usize, AABB); SAH_BINS]; 3];
+
+impl BVH<World> {
     pub fn from_world(world: World, time0: f32, time1: f32) -> Self {
-        let mut volumes = world.volumes();
-        let mut nodes = Vec::new();
-        let _index = Self::create_node(&mut nodes, &mut volumes, 0, time0, time1);
-        Self { world, nodes }
+        Self::new(world, time0, time1)
     }
 
-    fn create_node(
-        nodes: &mut Vec<BVHNode2>,
-        volumes: &mut [(WorldIndex, AABB)],
-        axis: u8,
+    pub fn from_world_with(
+        world: World,
         time0: f32,
         time1: f32,
-    ) -> BVHNIndex {
+        split_method: SplitMethod,
+    ) -> Self {
+        Self::new_with(world, time0, time1, split_method)
+    }
+}
+
+impl<P: Primitives> BVH<P> {
+    /// Builds the tree over the boxes of the objects during `[time0, time1]`. Large trees are
+    /// built in parallel on the current rayon thread pool, `Renderer::install` runs the build
+    /// on the threads of a renderer. The tree is the same for any number of threads.
+    pub fn new(primitives: P, time0: f32, time1: f32) -> Self {
+        Self::new_with(primitives, time0, time1, SplitMethod::default())
+    }
+
+    pub fn new_with(primitives: P, time0: f32, time1: f32, split_method: SplitMethod) -> Self {
+        let mut bvh = Self {
+            primitives,
+            objects: Vec::new(),
+            nodes: Vec::new(),
+            parents: Vec::new(),
+            split_method,
+            build_cost: 0.0,
+        };
+        bvh.rebuild(time0, time1);
+        bvh
+    }
+
+    pub fn primitives(&self) -> &P {
+        &self.primitives
+    }
+
+    /// The objects of the tree, to move them before calling `refit` or `update`. Objects may
+    /// not be added or removed without calling `rebuild`.
+    pub fn primitives_mut(&mut self) -> &mut P {
+        &mut self.primitives
+    }
+
+    /// Builds the tree from scratch with the boxes of the objects during `[time0, time1]`.
+    pub fn rebuild(&mut self, time0: f32, time1: f32) {
+        let mut objects = self.primitives.volumes(time0, time1);
+        let mut nodes = Vec::with_capacity(2 * objects.len());
+        match self.split_method {
+            SplitMethod::Median => Self::create_node(&mut nodes, &mut objects, 0, 0),
+            SplitMethod::Sah { max_leaf_size } => Self::create_sah_node(
+                &mut nodes,
+                &mut objects,
+                0,
+                0,
+                max_leaf_size.clamp(1, MAX_LEAF_SIZE),
+            ),
+        }
+        self.objects = objects;
+        self.parents = parents(&nodes);
+        self.nodes = nodes;
+        self.build_cost = self.sah_cost();
+    }
+
+    /// Updates the boxes of all nodes to the objects' boxes during `[time0, time1]` after they
+    /// moved, keeping the structure of the tree. This is much faster than `rebuild` but the
+    /// tree gets slower to traverse the further objects move from where it was built.
+    pub fn refit(&mut self, time0: f32, time1: f32) {
+        if self.objects.is_empty() {
+            return;
+        }
+        for (object, aabb) in self.objects.iter_mut() {
+            *aabb = self.primitives.primitive_bounding_box(object, time0, time1);
+        }
+        // children are stored after their parents
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            let aabb = if node.is_leaf() {
+                let start = node.offset as usize;
+                bounds(&self.objects[start..start + node.count as usize])
+            } else {
+                let left = index + 1;
+                let right = self.nodes[left].skip(left);
+                AABB::surrounding_box(self.nodes[left].aabb(), self.nodes[right].aabb())
+            };
+            self.nodes[index].set_aabb(aabb);
+        }
+    }
+
+    /// Refits the tree, and rebuilds it instead when the refitted tree's SAH cost is more than
+    /// `max_cost_ratio` times the cost right after the last build. Returns whether it rebuilt.
+    pub fn update(&mut self, time0: f32, time1: f32, max_cost_ratio: f32) -> bool {
+        self.refit(time0, time1);
+        if self.sah_cost() > max_cost_ratio * self.build_cost {
+            self.rebuild(time0, time1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// SAH cost of the tree right after it was last built, see `sah_cost`.
+    pub fn build_cost(&self) -> f32 {
+        self.build_cost
+    }
+
+    /// Calls `visit` with every object in the leaves whose boxes the ray enters between `t_min`
+    /// and `t_max`. `visit` returns the new `t_max`.
+    ///
+    /// Visits the child on the side the ray comes from first, so hits shrink `t_max` before
+    /// the farther child is tested. Needs no stack: once a subtree is done the walk goes up
+    /// through the parent links until it leaves a near child, and continues with its sibling.
+    fn traverse<F: FnMut(&P::Index, f32) -> f32>(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        mut t_max: f32,
+        mut visit: F,
+    ) {
+        if self.objects.is_empty() {
+            return;
+        }
+        let mut index = 0;
+        loop {
+            let node = &self.nodes[index];
+            if node.aabb().hit(ray, t_min, t_max).is_some() {
+                if !node.is_leaf() {
+                    index = self.children(index, ray).0;
+                    continue;
+                }
+                let start = node.offset as usize;
+                for (object, _) in &self.objects[start..start + node.count as usize] {
+                    t_max = visit(object, t_max);
+                }
+            }
+            loop {
+                if index == 0 {
+                    return;
+                }
+                let parent = self.parents[index] as usize;
+                let (near, far) = self.children(parent, ray);
+                if index == near {
+                    index = far;
+                    break;
+                }
+                index = parent;
+            }
+        }
+    }
+
+    /// Expected cost of intersecting a random ray with the tree according to the surface area
+    /// heuristic, lower is better.
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = self.nodes[0].aabb().surface_area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.is_leaf() {
+                    node.count as f32
+                } else {
+                    TRAVERSAL_COST
+                };
+                cost * node.aabb().surface_area()
+            })
+            .sum::<f32>()
+            / root_area
+    }
+
+    /// Adds a leaf for `volumes`, the objects starting at `offset` in `BVH::objects`.
+    fn push_leaf(nodes: &mut Vec<LinearNode>, volumes: &[(P::Index, AABB)], offset: usize) {
+        let mut node = LinearNode::new(bounds(volumes));
+        node.count = volumes.len() as u16;
+        node.offset = if volumes.is_empty() {
+            // an empty tree is an interior node without children
+            nodes.len() as u32 + 1
+        } else {
+            offset as u32
+        };
+        nodes.push(node);
+    }
+
+    /// Adds an interior node with the box `aabb`, `build_children` adds the nodes of both
+    /// children.
+    fn push_interior<F: FnOnce(&mut Vec<LinearNode>)>(
+        nodes: &mut Vec<LinearNode>,
+        aabb: AABB,
+        axis: u8,
+        build_children: F,
+    ) {
+        let index = nodes.len();
+        let mut node = LinearNode::new(aabb);
+        node.axis = axis;
+        nodes.push(node);
+        build_children(nodes);
+        nodes[index].offset = nodes.len() as u32;
+    }
+
+    fn create_node(
+        nodes: &mut Vec<LinearNode>,
+        volumes: &mut [(P::Index, AABB)],
+        offset: usize,
+        axis: u8,
+    ) {
+        if volumes.len() <= 1 {
+            return Self::push_leaf(nodes, volumes, offset);
+        }
         let cmp = match axis {
             0 => Self::x_cmp,
             1 => Self::y_cmp,
             _ => Self::z_cmp,
         };
-        let axis = (axis + 1) % 3;
-        let (left, right) = match volumes.len() {
-            1 => return BVHNIndex::WorldIndex(volumes[0]),
-            2 => match cmp(&volumes[0], &volumes[1]) {
-                Ordering::Less => (
-                    BVHNIndex::WorldIndex(volumes[0]),
-                    BVHNIndex::WorldIndex(volumes[1]),
-                ),
-                Ordering::Greater => (
-                    BVHNIndex::WorldIndex(volumes[1]),
-                    BVHNIndex::WorldIndex(volumes[0]),
-                ),
-                Ordering::Equal => (
-                    BVHNIndex::WorldIndex(volumes[0]),
-                    BVHNIndex::WorldIndex(volumes[1]),
-                ),
-            },
-            _ => {
-                volumes.sort_by(cmp);
-                let (left, right) = volumes.split_at_mut(volumes.len() / 2);
-                (
-                    Self::create_node(nodes, left, axis, time0, time1),
-                    Self::create_node(nodes, right, axis, time0, time1),
-                )
-            }
-        };
-        let left_box = match left {
-            BVHNIndex::Node(index) => nodes[index].aabb,
-            BVHNIndex::WorldIndex((_, aabb)) => aabb,
+        let next_axis = (axis + 1) % 3;
+        volumes.sort_by(cmp);
+        let middle = volumes.len() / 2;
+        let (aabb, _) = bounds_and_centroids(volumes);
+        Self::push_interior(nodes, aabb, axis, |nodes| {
+            Self::build_children(nodes, volumes, middle, offset, |nodes, volumes, offset| {
+                Self::create_node(nodes, volumes, offset, next_axis)
+            });
+        });
+    }
+
+    fn create_sah_node(
+        nodes: &mut Vec<LinearNode>,
+        volumes: &mut [(P::Index, AABB)],
+        offset: usize,
+        depth: usize,
+        max_leaf_size: usize,
+    ) {
+        if volumes.len() <= 1 {
+            return Self::push_leaf(nodes, volumes, offset);
+        }
+        let (aabb, centroid_bounds) = bounds_and_centroids(volumes);
+        let area = aabb.surface_area();
+
+        // best split as (cost, axis, first bin of the right child)
+        let mut best: Option<(f32, usize, usize)> = None;
+        let all_bins = if depth < MAX_SAH_DEPTH {
+            bin_volumes(volumes, &centroid_bounds)
+        } else {
+            [[(0, AABB::empty()); SAH_BINS]; 3]
         };
-        let right_box = match right {
-            BVHNIndex::Node(index) => nodes[index].aabb,
-            BVHNIndex::WorldIndex((_, aabb)) => aabb,
+        for (axis, bins) in all_bins.iter().enumerate() {
+            let (min, max) = (centroid_bounds.minimum[axis], centroid_bounds.maximum[axis]);
+            if max <= min || depth >= MAX_SAH_DEPTH {
+                continue;
+            }
+            // areas and counts of all bins to the right of each split, then sweep from the left
+            let mut right = [(0usize, 0.0f32); SAH_BINS];
+            let (mut count, mut bounds) = (0, AABB::empty());
+            for split in (1..SAH_BINS).rev() {
+                count += bins[split].0;
+                bounds = AABB::surrounding_box(bounds, bins[split].1);
+                right[split] = (count, bounds.surface_area());
+            }
+            let (mut count, mut bounds) = (0, AABB::empty());
+            for split in 1..SAH_BINS {
+                count += bins[split - 1].0;
+                bounds = AABB::surrounding_box(bounds, bins[split - 1].1);
+                let (right_count, right_area) = right[split];
+                if count == 0 || right_count == 0 {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST
+                    + (count as f32 * bounds.surface_area() + right_count as f32 * right_area)
+                        / area;
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, split));
+                }
+            }
+        }
+
+        let (middle, axis) = match best {
+            Some((cost, _, _))
+                if volumes.len() <= max_leaf_size && cost >= volumes.len() as f32 =>
+            {
+                return Self::push_leaf(nodes, volumes, offset);
+            }
+            Some((_, axis, split)) => {
+                let mut middle = 0;
+                for i in 0..volumes.len() {
+                    if bin(&centroid_bounds, axis, &volumes[i].1) < split {
+                        volumes.swap(i, middle);
+                        middle += 1;
+                    }
+                }
+                (middle, axis)
+            }
+            // all centroids coincide, splitting cannot separate the objects
+            None if volumes.len() <= max_leaf_size => {
+                return Self::push_leaf(nodes, volumes, offset);
+            }
+            // split at the median of the widest axis
+            None => {
+                let extent = centroid_bounds.maximum - centroid_bounds.minimum;
+                let axis = if extent.x >= extent.y && extent.x >= extent.z {
+                    0
+                } else if extent.y >= extent.z {
+                    1
+                } else {
+                    2
+                };
+                let middle = volumes.len() / 2;
+                volumes.select_nth_unstable_by(middle, |a, b| {
+                    centroid(&a.1)[axis]
+                        .partial_cmp(&centroid(&b.1)[axis])
+                        .unwrap_or(Ordering::Equal)
+                });
+                (middle, axis)
+            }
         };
-        let aabb = AABB::surrounding_box(left_box, right_box);
+        Self::push_interior(nodes, aabb, axis as u8, |nodes| {
+            Self::build_children(nodes, volumes, middle, offset, |nodes, volumes, offset| {
+                Self::create_sah_node(nodes, volumes, offset, depth + 1, max_leaf_size)
+            });
+        });
+    }
 
-        nodes.push(BVHNode2 { left, right, aabb });
-        BVHNIndex::Node(nodes.len() - 1)
+    /// Builds the children of the objects `volumes` starting at `offset` split at `middle`
+    /// with `build`. Large nodes build the right child on another thread into its own nodes,
+    /// which are appended after the left child's, so the tree does not depend on the threads.
+    fn build_children<F>(
+        nodes: &mut Vec<LinearNode>,
+        volumes: &mut [(P::Index, AABB)],
+        middle: usize,
+        offset: usize,
+        build: F,
+    ) where
+        F: Fn(&mut Vec<LinearNode>, &mut [(P::Index, AABB)], usize) + Sync,
+    {
+        let parallel = volumes.len() >= PARALLEL_BUILD_SIZE;
+        let (left, right) = volumes.split_at_mut(middle);
+        if !parallel {
+            build(nodes, left, offset);
+            build(nodes, right, offset + middle);
+            return;
+        }
+        let ((), right_nodes) = rayon::join(
+            || build(nodes, left, offset),
+            || {
+                let mut right_nodes = Vec::with_capacity(2 * right.len());
+                build(&mut right_nodes, right, offset + middle);
+                right_nodes
+            },
+        );
+        // skip indices of the right child's nodes count from its first node
+        let base = nodes.len() as u32;
+        nodes.extend(right_nodes.into_iter().map(|mut node| {
+            if !node.is_leaf() {
+                node.offset += base;
+            }
+            node
+        }));
     }
-    fn x_cmp(box_a: &(WorldIndex, AABB), box_b: &(WorldIndex, AABB)) -> Ordering {
+
+    fn x_cmp(box_a: &(P::Index, AABB), box_b: &(P::Index, AABB)) -> Ordering {
         box_a.1.minimum.x.partial_cmp(&box_b.1.minimum.x).unwrap()
     }
-    fn y_cmp(box_a: &(WorldIndex, AABB), box_b: &(WorldIndex, AABB)) -> Ordering {
+    fn y_cmp(box_a: &(P::Index, AABB), box_b: &(P::Index, AABB)) -> Ordering {
         box_a.1.minimum.y.partial_cmp(&box_b.1.minimum.y).unwrap()
     }
-    fn z_cmp(box_a: &(WorldIndex, AABB), box_b: &(WorldIndex, AABB)) -> Ordering {
+    fn z_cmp(box_a: &(P::Index, AABB), box_b: &(P::Index, AABB)) -> Ordering {
         box_a.1.minimum.z.partial_cmp(&box_b.1.minimum.z).unwrap()
     }
 
-    fn check_node(
-        &self,
-        index: usize,
-        ray: &Ray,
-        t_min: f32,
-        t_max: f32,
-    ) -> Option<&(WorldIndex, AABB)> {
-        let node = &self.nodes[index];
-        if node.aabb.hit(ray, t_min, t_max) {
-            let left = match node.left {
-                BVHNIndex::Node(index) => self.check_node(index, ray, t_min, t_max),
-                BVHNIndex::WorldIndex(ref l) => Some(l),
-            };
-            let right = match node.right {
-                BVHNIndex::Node(index) => self.check_node(index, ray, t_min, t_max),
-                BVHNIndex::WorldIndex(ref r) => Some(r),
-            };
-            match (left, right) {
-                (Some(l), Some(r)) => {
-                    let (left_index, left_aabb) = l;
-                    let (right_index, right_aabb) = r;
-                    match (
-                        left_aabb.hit(ray, t_min, t_max),
-                        right_aabb.hit(ray, t_min, t_max),
-                    ) {
-                        (true, true) => {
-                            let left_hit = self.world.hit_object(left_index, ray, t_min, t_max);
-                            let right_hit = self.world.hit_object(right_index, ray, t_min, t_max);
-                            match (left_hit, right_hit) {
-                                (Some(lh), Some(rh)) => {
-                                    if lh.t < rh.t {
-                                        Some(l)
-                                    } else {
-                                        Some(r)
-                                    }
-                                }
-                                (Some(_), _) => Some(l),
-                                (_, Some(_)) => Some(r),
-                                (_, _) => None,
-                            }
-                        }
-                        (false, true) => Some(l),
-                        (true, false) => Some(r),
-                        _ => None,
-                    }
+    /// The near and the far child of the interior node `index` for `ray`.
+    fn children(&self, index: usize, ray: &Ray) -> (usize, usize) {
+        let left = index + 1;
+        let right = self.nodes[left].skip(left);
+        if ray.direction[self.nodes[index].axis as usize] < 0.0 {
+            (right, left)
+        } else {
+            (left, right)
+        }
+    }
+}
+
+fn bounds<I>(volumes: &[(I, AABB)]) -> AABB {
+    volumes.iter().fold(AABB::empty(), |bounds, (_, aabb)| {
+        AABB::surrounding_box(bounds, *aabb)
+    })
+}
+
+/// Index of the parent of every node, `0` for the root.
+fn parents(nodes: &[LinearNode]) -> Vec<u32> {
+    let mut parents = vec![0; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        // the root of an empty tree is an interior node without children
+        if !node.is_leaf() && index + 1 < nodes.len() {
+            let left = index + 1;
+            parents[left] = index as u32;
+            parents[nodes[left].skip(left)] = index as u32;
+        }
+    }
+    parents
+}
+
+fn centroid(aabb: &AABB) -> Point3 {
+    0.5 * (aabb.minimum + aabb.maximum)
+}
+
+/// Bin of the centroid of `aabb` along `axis` between the bounds of all centroids.
+fn bin(centroid_bounds: &AABB, axis: usize, aabb: &AABB) -> usize {
+    let (min, max) = (centroid_bounds.minimum[axis], centroid_bounds.maximum[axis]);
+    (((centroid(aabb)[axis] - min) / (max - min) * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+}
+
+/// Box of `volumes` and box of their centroids. Boxes are merged with min and max, which give
+/// the same result in any order, so parallel chunks do not change the tree.
+fn bounds_and_centroids<I: Sync>(volumes: &[(I, AABB)]) -> (AABB, AABB) {
+    let fold = |volumes: &[(I, AABB)]| {
+        volumes.iter().fold(
+            (AABB::empty(), AABB::empty()),
+            |(bounds, centroids), (_, aabb)| {
+                (
+                    AABB::surrounding_box(bounds, *aabb),
+                    centroids.include(&centroid(aabb)),
+                )
+            },
+        )
+    };
+    if volumes.len() < 2 * PARALLEL_CHUNK_SIZE {
+        return fold(volumes);
+    }
+    volumes.par_chunks(PARALLEL_CHUNK_SIZE).map(fold).reduce(
+        || (AABB::empty(), AABB::empty()),
+        |a, b| {
+            (
+                AABB::surrounding_box(a.0, b.0),
+                AABB::surrounding_box(a.1, b.1),
+            )
+        },
+    )
+}
+
+/// Bins `volumes` along all axes at once.
+fn bin_volumes<I: Sync>(volumes: &[(I, AABB)], centroid_bounds: &AABB) -> Bins {
+    let fold = |volumes: &[(I, AABB)]| {
+        let mut bins = [[(0, AABB::empty()); SAH_BINS]; 3];
+        for (_, aabb) in volumes {
+            for (axis, axis_bins) in bins.iter_mut().enumerate() {
+                let (count, bounds) = &mut axis_bins[bin(centroid_bounds, axis, aabb)];
+                *count += 1;
+                *bounds = AABB::surrounding_box(*bounds, *aabb);
+            }
+        }
+        bins
+    };
+    if volumes.len() < 2 * PARALLEL_CHUNK_SIZE {
+        return fold(volumes);
+    }
+    volumes.par_chunks(PARALLEL_CHUNK_SIZE).map(fold).reduce(
+        || [[(0, AABB::empty()); SAH_BINS]; 3],
+        |mut a, b| {
+            for (axis_a, axis_b) in a.iter_mut().zip(b.iter()) {
+                for (bin_a, bin_b) in axis_a.iter_mut().zip(axis_b.iter()) {
+                    bin_a.0 += bin_b.0;
+                    bin_a.1 = AABB::surrounding_box(bin_a.1, bin_b.1);
                 }
-                (Some(left), None) => Some(left),
-                (None, Some(right)) => Some(right),
-                _ => None,
             }
-        } else {
-            None
+            a
+        },
+    )
+}
+
+impl<P: Primitives> Hittable for BVH<P> {
+    /// Boxes behind the closest hit so far are missed.
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let mut record = None;
+        self.traverse(ray, t_min, t_max, |object, closest| {
+            match self.primitives.hit_primitive(object, ray, t_min, closest) {
+                Some(hit) => {
+                    let t = hit.t;
+                    record = Some(hit);
+                    t
+                }
+                None => closest,
+            }
+        });
+        record
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
+        self.nodes[0].aabb()
+    }
+
+    /// Average of the densities of all objects, only objects whose boxes are in the direction
+    /// are asked for theirs.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        if self.objects.is_empty() {
+            return 0.0;
+        }
+        let mut sum = 0.0;
+        let ray = Ray::new(*origin, *direction, 0.0);
+        self.traverse(&ray, 0.0, f32::INFINITY, |object, t_max| {
+            sum += self
+                .primitives
+                .primitive_pdf_value(object, origin, direction);
+            t_max
+        });
+        sum / self.objects.len() as f32
+    }
+
+    /// Direction towards a uniformly picked object.
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        if self.objects.is_empty() {
+            return Vec3::new(1.0, 0.0, 0.0);
         }
+        let index =
+            ((sampler.get_1d() * self.objects.len() as f32) as usize).min(self.objects.len() - 1);
+        self.primitives
+            .primitive_random(&self.objects[index].0, origin, sampler)
     }
 }
 
-impl Hittable for BVH {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let (index, _) = self.check_node(self.nodes.len() - 1, ray, t_min, t_max)?;
-        self.world.hit_object(&index, ray, t_min, t_max)
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::bvh4::BVH4;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::objects::{Box3d, MovingSphere, Sphere, XYRect, XZRect};
+    use crate::texture::SolidTexture;
+    use crate::vec3::Vec3;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    type Objects = Vec<Box<dyn Hittable + Send + Sync>>;
+
+    pub(crate) fn random_world(seed: u64, objects: usize) -> World {
+        let mut world = World::default();
+        for object in random_objects(seed, objects) {
+            world.add(object);
+        }
+        world
+    }
+
+    fn random_objects(seed: u64, objects: usize) -> Objects {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let point = |rng: &mut StdRng| {
+            Point3::new(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+            )
+        };
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        let mut list: Objects = Vec::new();
+        for _ in 0..objects {
+            let center = point(&mut rng);
+            let size = rng.gen_range(0.1..2.0);
+            match rng.gen_range(0..3) {
+                0 => list.push(Box::new(Sphere::new(center, size, material))),
+                1 => list.push(Box::new(Box3d::new(
+                    center,
+                    center + Vec3::new(size, size * 0.5, size * 2.0),
+                    material,
+                ))),
+                _ => list.push(Box::new(XYRect::new(
+                    center.x,
+                    center.x + size,
+                    center.y,
+                    center.y + size,
+                    center.z,
+                    material,
+                ))),
+            }
+        }
+        list
+    }
+
+    #[test]
+    fn bvh_node_size() {
+        assert_eq!(std::mem::size_of::<LinearNode>(), 32);
     }
 
-    fn bounding_box(&self) -> AABB {
-        self.nodes[self.nodes.len() - 1].aabb
+    #[test]
+    fn bvh_matches_world() {
+        for (seed, objects) in [(1, 1), (2, 7), (3, 300)] {
+            for split_method in [SplitMethod::Median, SplitMethod::default()] {
+                let world = random_world(seed, objects);
+                let bvh = BVH::from_world_with(random_world(seed, objects), 0.0, 1.0, split_method);
+                let mut rng = StdRng::seed_from_u64(seed);
+                for _ in 0..2000 {
+                    let origin = Point3::new(
+                        rng.gen_range(-15.0..15.0),
+                        rng.gen_range(-15.0..15.0),
+                        rng.gen_range(-15.0..15.0),
+                    );
+                    let target = Point3::new(
+                        rng.gen_range(-10.0..10.0),
+                        rng.gen_range(-10.0..10.0),
+                        rng.gen_range(-10.0..10.0),
+                    );
+                    let ray = Ray::new(origin, target - origin, 0.0);
+                    let t_max = rng.gen_range(0.5..2.0);
+                    let expected = world.hit(&ray, 0.001, t_max);
+                    let actual = bvh.hit(&ray, 0.001, t_max);
+                    assert_eq!(
+                        expected.as_ref().map(|hit| (hit.t, hit.object_id)),
+                        actual.as_ref().map(|hit| (hit.t, hit.object_id)),
+                        "{:?} with {} objects",
+                        split_method,
+                        objects
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_moving_objects() {
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        let mut world = random_world(8, 20);
+        // moves 2 units along x during the shutter, twice as far as between its own times
+        world.add(MovingSphere::new(
+            Point3::new(20.0, 20.0, 20.0),
+            Point3::new(21.0, 20.0, 20.0),
+            0.0,
+            0.5,
+            0.5,
+            material,
+        ));
+        let aabb = world.bounding_box(0.0, 1.0);
+        assert_eq!(aabb.maximum.x, 22.5);
+
+        let bvh = BVH::from_world(world, 0.0, 1.0);
+        for &(x, time) in &[(20.0, 0.0), (21.0, 0.5), (22.0, 1.0)] {
+            let ray = Ray::new(Point3::new(x, 20.0, 30.0), Vec3::new(0.0, 0.0, -1.0), time);
+            let hit = bvh.hit(&ray, 0.001, f32::INFINITY).unwrap();
+            assert!((hit.t - 9.5).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn bvh_near_child_first() {
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        // spheres along the diagonal, split along every axis by the median builder
+        let spheres = (0..10)
+            .map(|i| Sphere::new(Point3::new(1.0, 1.0, 1.0) * (i as f32 * 3.0), 1.0, material))
+            .collect::<Vec<_>>();
+        let bvh = BVH::new_with(spheres, 0.0, 1.0, SplitMethod::Median);
+        for direction in [1.0, -1.0] {
+            let ray = Ray::new(
+                Point3::new(1.0, 1.0, 1.0) * (13.5 - 100.0 * direction),
+                Vec3::new(1.0, 1.0, 1.0) * direction,
+                0.0,
+            );
+            let mut visited = Vec::new();
+            bvh.traverse(&ray, 0.0, f32::INFINITY, |index, t_max| {
+                visited.push(bvh.primitives[*index].center.x * direction);
+                t_max
+            });
+            assert_eq!(visited.len(), 10);
+            assert!(visited.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn bvh_of_hittable_list() {
+        let objects = random_objects(9, 100);
+        let bvh = BVH::new(random_objects(9, 100), 0.0, 1.0);
+        let mut rng = StdRng::seed_from_u64(9);
+        for _ in 0..1000 {
+            let origin = Point3::new(
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+                rng.gen_range(-15.0..15.0),
+            );
+            let ray = Ray::new(origin, -origin, 0.0);
+            let expected = objects
+                .iter()
+                .filter_map(|object| object.hit(&ray, 0.001, f32::INFINITY))
+                .map(|hit| hit.t)
+                .min_by(f32::total_cmp);
+            let actual = bvh.hit(&ray, 0.001, f32::INFINITY).map(|hit| hit.t);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn bvh_light_sampling() {
+        // objects that can be sampled
+        let light_objects = || {
+            let light = DiffuseLight::new(SolidTexture::from_rgb(4.0, 4.0, 4.0));
+            let mut rng = StdRng::seed_from_u64(10);
+            let mut objects: Objects = Vec::new();
+            for i in 0..30 {
+                let (x, y, z) = (
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                );
+                if i % 2 == 0 {
+                    objects.push(Box::new(Sphere::new(Point3::new(x, y, z), 0.5, light)));
+                } else {
+                    objects.push(Box::new(XZRect::new(x, x + 1.0, z, z + 1.0, y, light)));
+                }
+            }
+            objects
+        };
+        let objects = light_objects();
+        let lights = BVH::new(light_objects(), 0.0, 1.0);
+        let mut sampler = crate::sampler::RandomSampler::new(10);
+        let origin = Point3::new(0.0, 0.0, 20.0);
+        for i in 0..1000 {
+            sampler.start_pixel_sample((i, 0), 0);
+            let direction = lights.random(&origin, &mut sampler);
+            let pdf = lights.pdf_value(&origin, &direction);
+            let expected = objects
+                .iter()
+                .map(|object| object.pdf_value(&origin, &direction))
+                .sum::<f32>()
+                / objects.len() as f32;
+            assert!(pdf > 0.0);
+            assert!(
+                (pdf - expected).abs() <= 1e-4 * expected,
+                "{} {}",
+                pdf,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn bvh_empty_lights() {
+        let lights = BVH::new(Objects::new(), 0.0, 1.0);
+        let origin = Point3::new(0.0, 1.0, 0.0);
+        let direction = Vec3::new(0.0, 1.0, 0.0);
+        assert_eq!(lights.pdf_value(&origin, &direction), 0.0);
+        assert_eq!(BVH4::from_bvh(lights).pdf_value(&origin, &direction), 0.0);
+        assert_eq!(World::default().pdf_value(&origin, &direction), 0.0);
+    }
+
+    #[test]
+    fn bvh_refit() {
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut point = |scale: f32| {
+            scale
+                * Point3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                )
+        };
+        let spheres: Vec<_> = (0..200)
+            .map(|_| Sphere::new(point(10.0), 0.5, material))
+            .collect();
+        let mut bvh = BVH::new(spheres.clone(), 0.0, 1.0);
+        let mut world = World::default();
+        for sphere in spheres.iter() {
+            world.add(*sphere);
+        }
+        let mut world_bvh = BVH::from_world(world, 0.0, 1.0);
+
+        // small moves are refitted, scrambling every object rebuilds the tree
+        for &(distance, rebuilt) in &[(0.2, false), (10.0, true)] {
+            let moves: Vec<_> = (0..200).map(|_| point(distance)).collect();
+            for (sphere, offset) in bvh.primitives_mut().iter_mut().zip(moves.iter()) {
+                sphere.center += *offset;
+            }
+            let world = world_bvh.primitives_mut();
+            for (sphere, offset) in world
+                .objects_mut::<Sphere<Lambertian<SolidTexture>>>()
+                .iter_mut()
+                .zip(moves.iter())
+            {
+                sphere.center += *offset;
+            }
+            assert_eq!(bvh.update(0.0, 1.0, 1.5), rebuilt);
+            world_bvh.refit(0.0, 1.0);
+            assert!(bvh.sah_cost() <= 1.5 * bvh.build_cost());
+
+            for _ in 0..500 {
+                let origin = point(20.0);
+                let ray = Ray::new(origin, point(10.0) - origin, 0.0);
+                let expected = bvh
+                    .primitives()
+                    .iter()
+                    .filter_map(|sphere| sphere.hit(&ray, 0.001, f32::INFINITY))
+                    .map(|hit| hit.t)
+                    .min_by(f32::total_cmp);
+                assert_eq!(
+                    bvh.hit(&ray, 0.001, f32::INFINITY).map(|hit| hit.t),
+                    expected
+                );
+                assert_eq!(
+                    world_bvh.hit(&ray, 0.001, f32::INFINITY).map(|hit| hit.t),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_parallel_build() {
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        let mut rng = StdRng::seed_from_u64(13);
+        // large enough to build subtrees and bins in parallel
+        let spheres: Vec<_> = (0..3 * PARALLEL_CHUNK_SIZE)
+            .map(|_| {
+                let center = Point3::new(
+                    rng.gen_range(-100.0..100.0),
+                    rng.gen_range(-100.0..100.0),
+                    rng.gen_range(-100.0..100.0),
+                );
+                Sphere::new(center, rng.gen_range(0.1..1.0), material)
+            })
+            .collect();
+        let tree = |bvh: &BVH<Vec<_>>| {
+            let nodes: Vec<_> = bvh
+                .nodes
+                .iter()
+                .map(|node| {
+                    let (min, max) = (node.minimum, node.maximum);
+                    let bits = [min.x, min.y, min.z, max.x, max.y, max.z].map(f32::to_bits);
+                    (bits, node.offset, node.count, node.axis)
+                })
+                .collect();
+            let objects: Vec<usize> = bvh.objects.iter().map(|&(index, _)| index).collect();
+            (nodes, objects)
+        };
+
+        for &split_method in &[SplitMethod::default(), SplitMethod::Median] {
+            let build = |threads: usize| {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap();
+                pool.install(|| tree(&BVH::new_with(spheres.clone(), 0.0, 1.0, split_method)))
+            };
+            let expected = build(1);
+            for &threads in &[2, 4] {
+                assert!(build(threads) == expected);
+            }
+        }
+
+        // worlds with the same objects give the same tree
+        let order = |world: World| {
+            let bvh = BVH::from_world(world, 0.0, 1.0);
+            bvh.objects
+                .iter()
+                .map(|&(index, _)| index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(random_world(5, 500)), order(random_world(5, 500)));
     }
 }
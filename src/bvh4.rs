@@ -0,0 +1,405 @@
+use crate::aabb::AABB;
+use crate::bvh::{Primitives, SplitMethod, BVH};
+use crate::hittable::{HitRecord, Hittable};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::{Point3, Vec3};
+use crate::world::World;
+
+/// Marks unused child slots of a node.
+const EMPTY: u32 = u32::MAX;
+/// Every popped node pushes at most 3 more entries than it pops, and the depth of the binary
+/// tree is bounded by the builder.
+const STACK_SIZE: usize = 256;
+
+/// Node with up to 4 children whose boxes are stored axis by axis, so they can be tested
+/// against a ray at once.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+struct BVH4Node {
+    minimum: [[f32; 4]; 3],
+    maximum: [[f32; 4]; 3],
+    /// Index of a child node, or of the first object of a leaf child, `EMPTY` for unused slots.
+    children: [u32; 4],
+    /// Number of objects of leaf children, `0` for interior children.
+    counts: [u32; 4],
+}
+
+impl BVH4Node {
+    fn empty() -> Self {
+        Self {
+            minimum: [[f32::INFINITY; 4]; 3],
+            maximum: [[f32::NEG_INFINITY; 4]; 3],
+            children: [EMPTY; 4],
+            counts: [0; 4],
+        }
+    }
+
+    fn set_box(&mut self, slot: usize, aabb: &AABB) {
+        for axis in 0..3 {
+            self.minimum[axis][slot] = aabb.minimum[axis];
+            self.maximum[axis][slot] = aabb.maximum[axis];
+        }
+    }
+}
+
+/// BVH with 4 children per node, built by collapsing a binary `BVH`. The boxes of all
+/// children of a node are intersected together with SSE on x86_64 and the children are
+/// visited front to back.
+pub struct BVH4<P: Primitives = World> {
+    bvh: BVH<P>,
+    nodes: Vec<BVH4Node>,
+}
+
+impl BVH4<World> {
+    pub fn from_world(world: World, time0: f32, time1: f32) -> Self {
+        Self::from_bvh(BVH::from_world(world, time0, time1))
+    }
+
+    pub fn from_world_with(
+        world: World,
+        time0: f32,
+        time1: f32,
+        split_method: SplitMethod,
+    ) -> Self {
+        Self::from_bvh(BVH::from_world_with(world, time0, time1, split_method))
+    }
+}
+
+impl<P: Primitives> BVH4<P> {
+    pub fn from_bvh(bvh: BVH<P>) -> Self {
+        let mut nodes = Vec::with_capacity(bvh.nodes.len() / 2);
+        if !bvh.objects.is_empty() {
+            Self::collapse(&bvh, &[0], &mut nodes);
+        }
+        Self { bvh, nodes }
+    }
+
+    /// Adds a node with the binary nodes `children`, expanding the interior children with the
+    /// largest area until the 4 slots are filled.
+    fn collapse(bvh: &BVH<P>, children: &[usize], nodes: &mut Vec<BVH4Node>) -> u32 {
+        let mut children = children.to_vec();
+        while children.len() < 4 {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, &child)| !bvh.nodes[child].is_leaf())
+                .max_by(|(_, &a), (_, &b)| {
+                    let area = |child: usize| bvh.nodes[child].aabb().surface_area();
+                    area(a).total_cmp(&area(b))
+                })
+                .map(|(position, _)| position);
+            let Some(position) = largest else {
+                break;
+            };
+            let child = children.remove(position);
+            children.extend_from_slice(&Self::binary_children(bvh, child));
+        }
+
+        let index = nodes.len();
+        nodes.push(BVH4Node::empty());
+        let mut node = BVH4Node::empty();
+        for (slot, &child) in children.iter().enumerate() {
+            let binary = &bvh.nodes[child];
+            node.set_box(slot, &binary.aabb());
+            if binary.is_leaf() {
+                node.children[slot] = binary.offset;
+                node.counts[slot] = binary.count as u32;
+            } else {
+                node.children[slot] =
+                    Self::collapse(bvh, &Self::binary_children(bvh, child), nodes);
+            }
+        }
+        nodes[index] = node;
+        index as u32
+    }
+
+    /// Indices of both children of the interior binary node `index`.
+    fn binary_children(bvh: &BVH<P>, index: usize) -> [usize; 2] {
+        let left = index + 1;
+        [left, bvh.nodes[left].skip(left)]
+    }
+}
+
+impl<P: Primitives> Hittable for BVH4<P> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let origin = [ray.origin.x, ray.origin.y, ray.origin.z];
+        let inverse_direction = [
+            1.0 / ray.direction.x,
+            1.0 / ray.direction.y,
+            1.0 / ray.direction.z,
+        ];
+
+        let mut closest = t_max;
+        let mut record = None;
+        // nodes to visit with the distance at which the ray enters them
+        let mut stack = [(0u32, 0.0f32); STACK_SIZE];
+        let mut size = 1;
+        while size > 0 {
+            size -= 1;
+            let (index, entry) = stack[size];
+            if entry > closest {
+                continue;
+            }
+            let node = &self.nodes[index as usize];
+            let (mask, entries) = intersect(node, &origin, &inverse_direction, t_min, closest);
+
+            let mut hits = [(0.0f32, 0usize); 4];
+            let mut hit_count = 0;
+            for (slot, &entry) in entries.iter().enumerate() {
+                if mask & (1 << slot) != 0 && node.children[slot] != EMPTY {
+                    hits[hit_count] = (entry, slot);
+                    hit_count += 1;
+                }
+            }
+            let hits = &mut hits[..hit_count];
+            hits.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+            // leaves are intersected right away front to back, interior children are pushed
+            // back to front so the nearest one is popped first
+            for &(_, slot) in hits.iter() {
+                if node.counts[slot] > 0 {
+                    let start = node.children[slot] as usize;
+                    let end = start + node.counts[slot] as usize;
+                    for (object, _) in &self.bvh.objects[start..end] {
+                        if let Some(hit) = self
+                            .bvh
+                            .primitives
+                            .hit_primitive(object, ray, t_min, closest)
+                        {
+                            closest = hit.t;
+                            record = Some(hit);
+                        }
+                    }
+                }
+            }
+            for &(entry, slot) in hits.iter().rev() {
+                if node.counts[slot] == 0 && entry <= closest {
+                    stack[size] = (node.children[slot], entry);
+                    size += 1;
+                }
+            }
+        }
+        record
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        self.bvh.bounding_box(time0, time1)
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        self.bvh.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.bvh.random(origin, sampler)
+    }
+}
+
+/// Slab test of the ray against the 4 child boxes of `node`, returns a bit mask of the hit
+/// children and the distances at which the ray enters them. Like `AABB::hit` a ray parallel
+/// to an axis (infinite inverse direction) hits a box only if it starts between its planes.
+#[cfg(target_arch = "x86_64")]
+fn intersect(
+    node: &BVH4Node,
+    origin: &[f32; 3],
+    inverse_direction: &[f32; 3],
+    t_min: f32,
+    t_max: f32,
+) -> (u32, [f32; 4]) {
+    use std::arch::x86_64::*;
+
+    // SSE is part of the x86_64 baseline and the node arrays are 16 byte aligned
+    unsafe {
+        let mut entry = _mm_set1_ps(t_min);
+        let mut exit = _mm_set1_ps(t_max);
+        let mut inside = 0b1111;
+        for axis in 0..3 {
+            let origin = _mm_set1_ps(origin[axis]);
+            let minimum = _mm_load_ps(node.minimum[axis].as_ptr());
+            let maximum = _mm_load_ps(node.maximum[axis].as_ptr());
+            if inverse_direction[axis].is_infinite() {
+                // (min - origin) * inf is NaN for rays starting on a plane, test the origin
+                let between =
+                    _mm_and_ps(_mm_cmple_ps(minimum, origin), _mm_cmple_ps(origin, maximum));
+                inside &= _mm_movemask_ps(between) as u32;
+                continue;
+            }
+            let inverse = _mm_set1_ps(inverse_direction[axis]);
+            let t0 = _mm_mul_ps(_mm_sub_ps(minimum, origin), inverse);
+            let t1 = _mm_mul_ps(_mm_sub_ps(maximum, origin), inverse);
+            entry = _mm_max_ps(_mm_min_ps(t0, t1), entry);
+            exit = _mm_min_ps(_mm_max_ps(t0, t1), exit);
+        }
+        let mask = _mm_movemask_ps(_mm_cmple_ps(entry, exit)) as u32 & inside;
+        let mut entries = [0.0; 4];
+        _mm_storeu_ps(entries.as_mut_ptr(), entry);
+        (mask, entries)
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn intersect(
+    node: &BVH4Node,
+    origin: &[f32; 3],
+    inverse_direction: &[f32; 3],
+    t_min: f32,
+    t_max: f32,
+) -> (u32, [f32; 4]) {
+    intersect_scalar(node, origin, inverse_direction, t_min, t_max)
+}
+
+/// Same as `intersect` one child at a time.
+#[cfg_attr(target_arch = "x86_64", allow(dead_code))]
+fn intersect_scalar(
+    node: &BVH4Node,
+    origin: &[f32; 3],
+    inverse_direction: &[f32; 3],
+    t_min: f32,
+    t_max: f32,
+) -> (u32, [f32; 4]) {
+    let mut mask = 0;
+    let mut entries = [0.0; 4];
+    for (slot, slot_entry) in entries.iter_mut().enumerate() {
+        let (mut entry, mut exit) = (t_min, t_max);
+        let mut inside = true;
+        for axis in 0..3 {
+            let (minimum, maximum) = (node.minimum[axis][slot], node.maximum[axis][slot]);
+            if inverse_direction[axis].is_infinite() {
+                inside &= minimum <= origin[axis] && origin[axis] <= maximum;
+                continue;
+            }
+            let t0 = (minimum - origin[axis]) * inverse_direction[axis];
+            let t1 = (maximum - origin[axis]) * inverse_direction[axis];
+            entry = t0.min(t1).max(entry);
+            exit = t0.max(t1).min(exit);
+        }
+        if inside && entry <= exit {
+            mask |= 1 << slot;
+        }
+        *slot_entry = entry;
+    }
+    (mask, entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bvh::test::random_world;
+    use crate::material::Lambertian;
+    use crate::objects::Sphere;
+    use crate::texture::SolidTexture;
+    use crate::vec3::Point3;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn bvh4_matches_world() {
+        for (seed, objects) in [(4, 1), (5, 6), (6, 500)] {
+            let world = random_world(seed, objects);
+            let bvh = BVH4::from_world(random_world(seed, objects), 0.0, 1.0);
+            let mut rng = StdRng::seed_from_u64(seed);
+            for i in 0..2000 {
+                let origin = Point3::new(
+                    rng.gen_range(-15.0..15.0),
+                    rng.gen_range(-15.0..15.0),
+                    rng.gen_range(-15.0..15.0),
+                );
+                let mut direction = Point3::new(
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                ) - origin;
+                // axis parallel rays
+                if i % 4 == 0 {
+                    direction.y = 0.0;
+                    direction.z = 0.0;
+                }
+                let ray = Ray::new(origin, direction, 0.0);
+                let expected = world.hit(&ray, 0.001, f32::INFINITY);
+                let actual = bvh.hit(&ray, 0.001, f32::INFINITY);
+                assert_eq!(
+                    expected.as_ref().map(|hit| (hit.t, hit.object_id)),
+                    actual.as_ref().map(|hit| (hit.t, hit.object_id)),
+                    "{} objects",
+                    objects
+                );
+            }
+        }
+        // an axis parallel ray starting on the plane of a box
+        let mut world = World::default();
+        world.add(Sphere::new(
+            Point3::new(5.0, 1.0, 0.0),
+            1.0,
+            Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5)),
+        ));
+        let ray = Ray::new(Point3::default(), Point3::new(1.0, 0.0, 0.0), 0.0);
+        let expected = world.hit(&ray, 0.001, f32::INFINITY).map(|hit| hit.t);
+        assert_eq!(expected, Some(5.0));
+        let bvh = BVH4::from_world(world, 0.0, 1.0);
+        assert_eq!(
+            bvh.hit(&ray, 0.001, f32::INFINITY).map(|hit| hit.t),
+            expected
+        );
+
+        assert!(BVH4::from_world(World::default(), 0.0, 1.0)
+            .hit(
+                &Ray::new(Point3::default(), Point3::new(1.0, 0.0, 0.0), 0.0),
+                0.0,
+                1.0
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn bvh4_simd_matches_scalar() {
+        let mut node = BVH4Node::empty();
+        node.set_box(
+            0,
+            &AABB::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0)),
+        );
+        node.set_box(
+            1,
+            &AABB::new(Point3::new(2.0, -1.0, -1.0), Point3::new(3.0, 1.0, 1.0)),
+        );
+        node.set_box(
+            2,
+            &AABB::new(Point3::new(0.0, 0.0, 5.0), Point3::new(1.0, 0.0, 6.0)),
+        );
+        let rays = [
+            // inside the first box, through the second
+            ([0.5, 0.5, 0.5], [1.0, 0.0, 0.0], 0b011),
+            // parallel to x on the boundary of the first two boxes
+            ([-1.0, 0.0, 0.5], [1.0, 0.0, 0.0], 0b011),
+            // parallel to z through the flat third box
+            ([0.5, 0.0, 0.0], [0.0, 0.0, 1.0], 0b101),
+            // pointing away
+            ([-1.0, 0.5, 0.5], [-1.0, 0.0, 0.0], 0b000),
+        ];
+        for &(origin, direction, expected) in rays.iter() {
+            let (origin, direction) = (&origin, &direction);
+            let inverse = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+            let (mask, entries) = intersect(&node, origin, &inverse, 0.0, 100.0);
+            let (scalar_mask, scalar_entries) =
+                intersect_scalar(&node, origin, &inverse, 0.0, 100.0);
+            assert_eq!(mask & 0b111, scalar_mask & 0b111, "{:?}", origin);
+            assert_eq!(mask & 0b111, expected, "{:?}", origin);
+            for slot in 0..3 {
+                if mask & (1 << slot) != 0 {
+                    assert_eq!(entries[slot], scalar_entries[slot]);
+                }
+            }
+        }
+        let (mask, _) = intersect(
+            &node,
+            &[0.5, 0.5, 0.5],
+            &[1.0, f32::INFINITY, f32::INFINITY],
+            0.0,
+            100.0,
+        );
+        assert_eq!(mask & 0b11, 0b11);
+    }
+}
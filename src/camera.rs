@@ -1,4 +1,5 @@
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::*;
 
 pub struct Camera {
@@ -54,16 +55,14 @@ impl Camera {
         }
     }
 
-    pub fn get_ray(&self, x: f32, y: f32) -> Ray {
-        let rd = self.lens_radius * Vec3::random_in_unit_disk();
+    pub fn get_ray(&self, x: f32, y: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius * Vec3::random_in_unit_disk(sampler);
         let offset = self.u * rd.x + self.v * rd.y;
 
         let dir =
             self.lower_left_corner + x * self.horizontal + y * self.vertical - self.origin - offset;
 
-        use rand::distributions::Distribution;
-        let mut rng = rand::thread_rng();
-        let uniform = rand::distributions::Uniform::new(self.time0, self.time1);
-        Ray::new(self.origin + offset, dir, uniform.sample(&mut rng))
+        let time = self.time0 + sampler.get_1d() * (self.time1 - self.time0);
+        Ray::new(self.origin + offset, dir, time)
     }
 }
@@ -0,0 +1,213 @@
+use rayon::prelude::*;
+
+use crate::tonemap::luminance;
+use crate::vec3::{Color, Vec3};
+
+/// Guide images for the [`Denoiser`], every buffer is stored row by row.
+pub struct DenoiseGuides<'a> {
+    pub albedo: &'a [Color],
+    pub normal: &'a [Vec3],
+    pub depth: &'a [f32],
+}
+
+/// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010): a joint bilateral filter
+/// repeated with growing gaps between the taps, guided by the albedo, normal and depth of
+/// the first hit. The albedo is divided out before filtering so textures stay sharp.
+#[derive(Debug, Clone, Copy)]
+pub struct Denoiser {
+    /// Number of filter passes, the filter radius is `2^iterations` pixels.
+    pub iterations: u32,
+    /// How much the (compressed) colors of two pixels may differ, halved every pass.
+    pub sigma_color: f32,
+    /// Exponent of the normal weight `max(0, n_p · n_q)^sigma_normal`.
+    pub sigma_normal: f32,
+    /// How much the albedos of two pixels may differ.
+    pub sigma_albedo: f32,
+    /// How much the depths of two pixels may differ relative to the depth.
+    pub sigma_depth: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_color: 0.6,
+            sigma_normal: 64.0,
+            sigma_albedo: 0.1,
+            sigma_depth: 0.05,
+        }
+    }
+}
+
+impl Denoiser {
+    const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+    /// Albedo channels below this value (e.g. of lights) are not divided out.
+    const MIN_ALBEDO: f32 = 1e-3;
+
+    /// Returns the filtered `color` image of `width` x `height` pixels.
+    pub fn denoise(
+        &self,
+        width: u32,
+        height: u32,
+        color: &[Color],
+        guides: &DenoiseGuides,
+    ) -> Vec<Color> {
+        let (width, height) = (width as usize, height as usize);
+        let demodulate = |value: f32| {
+            if value < Self::MIN_ALBEDO {
+                1.0
+            } else {
+                value
+            }
+        };
+        let albedo = guides
+            .albedo
+            .iter()
+            .map(|albedo| {
+                Color::new(
+                    demodulate(albedo.x),
+                    demodulate(albedo.y),
+                    demodulate(albedo.z),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut irradiance = color
+            .iter()
+            .zip(albedo.iter())
+            .map(|(color, albedo)| {
+                Color::new(color.x / albedo.x, color.y / albedo.y, color.z / albedo.z)
+            })
+            .collect::<Vec<_>>();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let sigma_color = self.sigma_color / (1 << iteration) as f32;
+            irradiance = (0..width * height)
+                .into_par_iter()
+                .map(|p| {
+                    self.filter_pixel(p, (width, height), step, sigma_color, &irradiance, guides)
+                })
+                .collect();
+        }
+
+        irradiance
+            .iter()
+            .zip(albedo.iter())
+            .map(|(irradiance, albedo)| *irradiance * *albedo)
+            .collect()
+    }
+
+    fn filter_pixel(
+        &self,
+        p: usize,
+        size: (usize, usize),
+        step: usize,
+        sigma_color: f32,
+        irradiance: &[Color],
+        guides: &DenoiseGuides,
+    ) -> Color {
+        let (x, y) = ((p % size.0) as isize, (p / size.0) as isize);
+        let color_p = Self::compress(&irradiance[p]);
+        let mut sum = Color::default();
+        let mut weights = 0.0;
+        for (j, kernel_y) in Self::KERNEL.iter().enumerate() {
+            let qy = y + (j as isize - 2) * step as isize;
+            if qy < 0 || qy >= size.1 as isize {
+                continue;
+            }
+            for (i, kernel_x) in Self::KERNEL.iter().enumerate() {
+                let qx = x + (i as isize - 2) * step as isize;
+                if qx < 0 || qx >= size.0 as isize {
+                    continue;
+                }
+                let q = qy as usize * size.0 + qx as usize;
+
+                let color_distance = (Self::compress(&irradiance[q]) - color_p).length_squared();
+                let albedo_distance = (guides.albedo[q] - guides.albedo[p]).length_squared();
+                let weight = kernel_x
+                    * kernel_y
+                    * (-color_distance / sigma_color.powi(2)).exp()
+                    * (-albedo_distance / self.sigma_albedo.powi(2)).exp()
+                    * self.geometry_weight(p, q, guides);
+                sum += irradiance[q] * weight;
+                weights += weight;
+            }
+        }
+        if weights > 0.0 {
+            sum / weights
+        } else {
+            irradiance[p]
+        }
+    }
+
+    fn geometry_weight(&self, p: usize, q: usize, guides: &DenoiseGuides) -> f32 {
+        let (depth_p, depth_q) = (guides.depth[p], guides.depth[q]);
+        match (depth_p.is_finite(), depth_q.is_finite()) {
+            (true, true) => {
+                let normal = guides.normal[p]
+                    .dot(&guides.normal[q])
+                    .max(0.0)
+                    .powf(self.sigma_normal);
+                let depth =
+                    (-(depth_p - depth_q).abs() / (self.sigma_depth * depth_p.max(1e-3))).exp();
+                normal * depth
+            }
+            // the background has no geometry, it is only blended with other background pixels
+            (false, false) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Compresses the HDR range so that bright pixels do not dominate the color weight.
+    #[inline]
+    fn compress(color: &Color) -> Color {
+        *color / (1.0 + luminance(color).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn denoiser_keeps_edges() {
+        // left half is red, right half is white, both lit with the same noisy light
+        let (width, height) = (16, 8);
+        let albedo = (0..width * height)
+            .map(|p| {
+                if p % width < width / 2 {
+                    Color::new(0.8, 0.1, 0.1)
+                } else {
+                    Color::new(0.8, 0.8, 0.8)
+                }
+            })
+            .collect::<Vec<_>>();
+        let normal = vec![Vec3::new(0.0, 0.0, 1.0); width * height];
+        let depth = vec![1.0; width * height];
+        let color = albedo
+            .iter()
+            .enumerate()
+            .map(|(p, albedo)| *albedo * if p % 3 == 0 { 1.5 } else { 0.75 })
+            .collect::<Vec<_>>();
+        let guides = DenoiseGuides {
+            albedo: &albedo,
+            normal: &normal,
+            depth: &depth,
+        };
+        let denoised = Denoiser::default().denoise(width as u32, height as u32, &color, &guides);
+
+        let noise = |image: &[Color]| {
+            image
+                .iter()
+                .zip(albedo.iter())
+                .map(|(color, albedo)| (*color - *albedo).length_squared())
+                .sum::<f32>()
+        };
+        assert!(noise(&denoised) < 0.1 * noise(&color));
+        // the filter does not blend the red and white halves
+        for (p, color) in denoised.iter().enumerate() {
+            let expected = if p % width < width / 2 { 0.1 } else { 0.8 };
+            assert!((color.y / color.x * 0.8 - expected).abs() < 1e-3);
+        }
+    }
+}
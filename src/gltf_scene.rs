@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use gltf::camera::Projection;
+use gltf::image::Format;
+use gltf::khr_lights_punctual::Kind;
+use gltf::mesh::Mode;
+
+use crate::aabb::AABB;
+use crate::camera::Camera;
+use crate::hittable::Hittable;
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+use crate::mesh::TriangleMesh;
+use crate::objects::Sphere;
+use crate::scene::{SceneMaterial, SceneTexture};
+use crate::texture::{ImageTexture, SolidTexture};
+use crate::tonemap::srgb_eotf;
+use crate::vec3::{Color, Point3, Vec3};
+use crate::world::World;
+
+/// Column major 4x4 matrix as used by glTF.
+type Matrix = [[f32; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// Camera of a glTF scene, placed by its node.
+#[derive(Debug, Clone, Copy)]
+pub struct GltfCamera {
+    pub look_from: Point3,
+    pub look_at: Point3,
+    pub v_up: Vec3,
+    /// Vertical field of view in degrees.
+    pub vfov: f32,
+    /// Aspect ratio stored in the file, if any.
+    pub aspect_ratio: Option<f32>,
+}
+
+impl GltfCamera {
+    /// Creates the camera, `aspect_ratio` is used when the file does not specify one.
+    pub fn camera(&self, aspect_ratio: f32) -> Camera {
+        Camera::new(
+            &self.look_from,
+            &self.look_at,
+            &self.v_up,
+            self.vfov,
+            self.aspect_ratio.unwrap_or(aspect_ratio),
+            0.0,
+            1.0,
+            0.0,
+            1.0,
+        )
+    }
+}
+
+/// Default scene of a glTF 2.0 file (`.gltf` with its buffers or `.glb`), with all node
+/// transforms applied.
+pub struct GltfScene {
+    /// Triangles of all meshes and the spheres standing in for punctual lights.
+    pub world: World,
+    /// Emissive meshes and light spheres, to be sampled as lights.
+    pub lights: World,
+    /// Perspective cameras in node order, orthographic cameras are skipped.
+    pub cameras: Vec<GltfCamera>,
+}
+
+impl GltfScene {
+    /// Loads the default scene of a glTF file, or its first scene if there is no default.
+    ///
+    /// Materials are mapped onto this crate's materials: emissive materials become
+    /// `DiffuseLight`, transmissive ones `Dielectric` with the `KHR_materials_ior` index,
+    /// metallic ones `Metal` with the roughness as fuzz and the others `Lambertian`. The base
+    /// color texture is multiplied by the base color factor, other textures are ignored.
+    ///
+    /// Point and spot lights become small emissive spheres emitting the same power, spot
+    /// cones and directional lights are not supported and skipped.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let (document, buffers, images) =
+            gltf::import(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .ok_or_else(|| format!("{}: the file has no scene", path.display()))?;
+
+        let mut loader = Loader {
+            buffers: &buffers,
+            images: &images,
+            materials: HashMap::new(),
+            textures: HashMap::new(),
+            point_lights: Vec::new(),
+            bounds: None,
+            scene: Self {
+                world: World::default(),
+                lights: World::default(),
+                cameras: Vec::new(),
+            },
+        };
+        for node in scene.nodes() {
+            loader
+                .node(&node, &IDENTITY)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        loader.add_point_lights();
+        Ok(loader.scene)
+    }
+}
+
+struct Loader<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    /// Converted materials by index, with whether they emit light.
+    materials: HashMap<Option<usize>, (SceneMaterial, bool)>,
+    /// Base color textures by image index and base color factor.
+    textures: HashMap<(usize, [u32; 3]), SceneTexture>,
+    /// Position and intensity of the point lights, added once the size of the scene is known.
+    point_lights: Vec<(Point3, Color)>,
+    bounds: Option<AABB>,
+    scene: GltfScene,
+}
+
+impl Loader<'_> {
+    fn node(&mut self, node: &gltf::Node, parent: &Matrix) -> Result<(), String> {
+        let transform = multiply(parent, &node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.primitive(&primitive, &transform)
+                    .map_err(|e| format!("mesh {}: {}", mesh.name().unwrap_or_default(), e))?;
+            }
+        }
+        if let Some(camera) = node.camera() {
+            if let Projection::Perspective(perspective) = camera.projection() {
+                let look_from = transform_point(&transform, &Point3::new(0.0, 0.0, 0.0));
+                let forward = transform_vector(&transform, &Vec3::new(0.0, 0.0, -1.0));
+                self.scene.cameras.push(GltfCamera {
+                    look_from,
+                    look_at: look_from + forward.unit(),
+                    v_up: transform_vector(&transform, &Vec3::new(0.0, 1.0, 0.0)).unit(),
+                    vfov: perspective.yfov().to_degrees(),
+                    aspect_ratio: perspective.aspect_ratio(),
+                });
+            }
+        }
+        if let Some(light) = node.light() {
+            if matches!(light.kind(), Kind::Point | Kind::Spot { .. }) {
+                let [r, g, b] = light.color();
+                self.point_lights.push((
+                    transform_point(&transform, &Point3::new(0.0, 0.0, 0.0)),
+                    light.intensity() * Color::new(r, g, b),
+                ));
+            }
+        }
+        for child in node.children() {
+            self.node(&child, &transform)?;
+        }
+        Ok(())
+    }
+
+    fn primitive(&mut self, primitive: &gltf::Primitive, transform: &Matrix) -> Result<(), String> {
+        if primitive.mode() != Mode::Triangles {
+            return Ok(());
+        }
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let positions: Vec<Point3> = reader
+            .read_positions()
+            .ok_or("primitive without positions")?
+            .map(|p| transform_point(transform, &Vec3::new(p[0], p[1], p[2])))
+            .collect();
+        let normal_matrix = normal_matrix(transform);
+        let normals = reader
+            .read_normals()
+            .map(|normals| {
+                normals
+                    .map(|n| transform_normal(&normal_matrix, &Vec3::new(n[0], n[1], n[2])))
+                    .collect()
+            })
+            .unwrap_or_default();
+        // glTF puts the origin of the texture at the top left
+        let uvs = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().map(|[u, v]| (u, 1.0 - v)).collect())
+            .unwrap_or_default();
+        let mut indices: Vec<[u32; 3]> = match reader.read_indices() {
+            Some(indices) => {
+                let indices: Vec<u32> = indices.into_u32().collect();
+                indices
+                    .chunks_exact(3)
+                    .map(|i| [i[0], i[1], i[2]])
+                    .collect()
+            }
+            None => (0..positions.len() as u32 / 3)
+                .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+                .collect(),
+        };
+        // mirroring transforms flip the winding order
+        if determinant(transform) < 0.0 {
+            for triangle in &mut indices {
+                triangle.swap(1, 2);
+            }
+        }
+        if indices.is_empty() {
+            return Ok(());
+        }
+
+        let (material, emissive) = self.material(&primitive.material())?;
+        let mesh = Arc::new(TriangleMesh::new(
+            positions, normals, uvs, indices, material,
+        )?);
+        let bounds = mesh.bounding_box(0.0, 0.0);
+        self.bounds = Some(match self.bounds {
+            Some(scene_bounds) => AABB::surrounding_box(scene_bounds, bounds),
+            None => bounds,
+        });
+        for triangle in TriangleMesh::triangles(&mesh) {
+            self.scene.world.add(triangle);
+        }
+        if emissive {
+            self.scene.lights.add(mesh);
+        }
+        Ok(())
+    }
+
+    fn material(&mut self, material: &gltf::Material) -> Result<(SceneMaterial, bool), String> {
+        if let Some(converted) = self.materials.get(&material.index()) {
+            return Ok(converted.clone());
+        }
+
+        let [r, g, b] = material.emissive_factor();
+        let emission = Color::new(r, g, b);
+        let converted: (SceneMaterial, bool) = if emission.x.max(emission.y).max(emission.z) > 0.0 {
+            (
+                Arc::new(DiffuseLight::new(SolidTexture::from_color(emission))),
+                true,
+            )
+        } else if material
+            .transmission()
+            .is_some_and(|transmission| transmission.transmission_factor() > 0.5)
+        {
+            (
+                Arc::new(Dielectric::new(material.ior().unwrap_or(1.5))),
+                false,
+            )
+        } else {
+            let pbr = material.pbr_metallic_roughness();
+            let [r, g, b, _] = pbr.base_color_factor();
+            let factor = Color::new(r, g, b);
+            let albedo: SceneTexture = match pbr.base_color_texture() {
+                Some(info) => self.texture(info.texture().source().index(), factor)?,
+                None => Arc::new(SolidTexture::from_color(factor)),
+            };
+            if pbr.metallic_factor() >= 0.5 {
+                (Arc::new(Metal::new(albedo, pbr.roughness_factor())), false)
+            } else {
+                (Arc::new(Lambertian::new(albedo)), false)
+            }
+        };
+        self.materials.insert(material.index(), converted.clone());
+        Ok(converted)
+    }
+
+    /// Converts an image to linear colors with the base color `factor` multiplied in. 8 and
+    /// 16 bit images are sRGB encoded like glTF requires for base colors, float ones linear.
+    fn texture(&mut self, image: usize, factor: Color) -> Result<SceneTexture, String> {
+        let key = (
+            image,
+            [factor.x.to_bits(), factor.y.to_bits(), factor.z.to_bits()],
+        );
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture.clone());
+        }
+
+        let data = &self.images[image];
+        let pixels = &data.pixels;
+        let byte = |value: u8| srgb_eotf(value as f32 / 255.0);
+        let rgb: Vec<[f32; 3]> = match data.format {
+            Format::R8 => pixels.iter().map(|&r| [byte(r); 3]).collect(),
+            Format::R8G8 => pixels
+                .chunks_exact(2)
+                .map(|p| [byte(p[0]), byte(p[1]), 0.0])
+                .collect(),
+            Format::R8G8B8 | Format::R8G8B8A8 => {
+                let stride = if data.format == Format::R8G8B8 { 3 } else { 4 };
+                pixels
+                    .chunks_exact(stride)
+                    .map(|p| [byte(p[0]), byte(p[1]), byte(p[2])])
+                    .collect()
+            }
+            Format::R16G16B16 | Format::R16G16B16A16 => {
+                let stride = if data.format == Format::R16G16B16 {
+                    6
+                } else {
+                    8
+                };
+                let value = |p: &[u8]| srgb_eotf(u16::from_le_bytes([p[0], p[1]]) as f32 / 65535.0);
+                pixels
+                    .chunks_exact(stride)
+                    .map(|p| [value(&p[0..2]), value(&p[2..4]), value(&p[4..6])])
+                    .collect()
+            }
+            Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => {
+                let stride = if data.format == Format::R32G32B32FLOAT {
+                    12
+                } else {
+                    16
+                };
+                let value = |p: &[u8]| f32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+                pixels
+                    .chunks_exact(stride)
+                    .map(|p| [value(&p[0..4]), value(&p[4..8]), value(&p[8..12])])
+                    .collect()
+            }
+            format => {
+                return Err(format!(
+                    "image {} has unsupported format {:?}",
+                    image, format
+                ))
+            }
+        };
+        let colors = rgb
+            .iter()
+            .map(|[r, g, b]| Color::new(r * factor.x, g * factor.y, b * factor.z))
+            .collect();
+        let texture: SceneTexture = Arc::new(
+            ImageTexture::from_linear(data.width, data.height, colors)
+                .map_err(|e| format!("image {}: {}", image, e))?,
+        );
+        self.textures.insert(key, texture.clone());
+        Ok(texture)
+    }
+
+    /// Adds a sphere emitting the power of a point light with intensity `I`, a sphere
+    /// of radius `r` with radiance `I / (π r²)` emits `4π I`.
+    fn add_point_lights(&mut self) {
+        let radius = match self.bounds {
+            Some(bounds) => 0.005 * (bounds.maximum - bounds.minimum).length(),
+            None => 0.01,
+        }
+        .max(1e-4);
+        for (position, intensity) in self.point_lights.drain(..) {
+            let radiance = intensity / (std::f32::consts::PI * radius * radius);
+            let material: SceneMaterial =
+                Arc::new(DiffuseLight::new(SolidTexture::from_color(radiance)));
+            self.scene
+                .world
+                .add(Sphere::new(position, radius, material.clone()));
+            self.scene
+                .lights
+                .add(Sphere::new(position, radius, material));
+        }
+    }
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.0; 4]; 4];
+    for (column, result_column) in result.iter_mut().enumerate() {
+        for (row, value) in result_column.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[k][row] * b[column][k]).sum();
+        }
+    }
+    result
+}
+
+fn transform_point(m: &Matrix, p: &Point3) -> Point3 {
+    transform_vector(m, p) + Vec3::new(m[3][0], m[3][1], m[3][2])
+}
+
+fn transform_vector(m: &Matrix, v: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[1][0] * v.y + m[2][0] * v.z,
+        m[0][1] * v.x + m[1][1] * v.y + m[2][1] * v.z,
+        m[0][2] * v.x + m[1][2] * v.y + m[2][2] * v.z,
+    )
+}
+
+fn determinant(m: &Matrix) -> f32 {
+    let column = |i: usize| Vec3::new(m[i][0], m[i][1], m[i][2]);
+    column(0).dot(&column(1).cross(&column(2)))
+}
+
+/// Inverse transpose of the upper 3x3 part of `m` up to a positive scale: the columns of
+/// the cofactor matrix, negated for mirroring transforms.
+fn normal_matrix(m: &Matrix) -> [Vec3; 3] {
+    let column = |i: usize| Vec3::new(m[i][0], m[i][1], m[i][2]);
+    let sign = determinant(m).signum();
+    [
+        sign * column(1).cross(&column(2)),
+        sign * column(2).cross(&column(0)),
+        sign * column(0).cross(&column(1)),
+    ]
+}
+
+fn transform_normal(normal_matrix: &[Vec3; 3], n: &Vec3) -> Vec3 {
+    (n.x * normal_matrix[0] + n.y * normal_matrix[1] + n.z * normal_matrix[2]).unit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ray::Ray;
+
+    #[test]
+    fn gltf_node_transforms() {
+        // a unit triangle in the xy plane, instanced by a translated parent node with a
+        // child scaled by -1 along x, and a camera and point light
+        let positions: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let uri = format!(
+            "data:application/octet-stream;base64,{}",
+            base64_encode(&positions)
+        );
+        let source = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "extensionsUsed": ["KHR_lights_punctual"],
+                "extensions": {{ "KHR_lights_punctual": {{ "lights": [
+                    {{ "type": "point", "color": [1.0, 0.5, 0.5], "intensity": 2.0 }}
+                ] }} }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0, 3, 4] }}],
+                "nodes": [
+                    {{ "translation": [0.0, 0.0, -5.0], "children": [1, 2] }},
+                    {{ "mesh": 0 }},
+                    {{ "mesh": 0, "scale": [-1.0, 1.0, 1.0] }},
+                    {{ "camera": 0, "translation": [0.0, 0.0, 1.0] }},
+                    {{ "extensions": {{ "KHR_lights_punctual": {{ "light": 0 }} }},
+                       "translation": [0.0, 3.0, 0.0] }}
+                ],
+                "cameras": [{{ "type": "perspective",
+                    "perspective": {{ "yfov": 0.5, "znear": 0.1 }} }}],
+                "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }} }}] }}],
+                "accessors": [{{ "bufferView": 0, "componentType": 5126, "count": 3,
+                    "type": "VEC3", "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0] }}],
+                "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+                "buffers": [{{ "byteLength": 36, "uri": "{}" }}]
+            }}"#,
+            uri
+        );
+        let dir = std::env::temp_dir().join("rust_raytracing_gltf_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("triangles.gltf");
+        std::fs::write(&path, source).unwrap();
+
+        let scene = GltfScene::load(&path).unwrap();
+        assert_eq!(scene.cameras.len(), 1);
+        let camera = scene.cameras[0];
+        assert!((camera.look_from - Point3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+        assert!((camera.look_at - Point3::new(0.0, 0.0, 0.0)).length() < 1e-6);
+        assert!((camera.vfov - 0.5f32.to_degrees()).abs() < 1e-4);
+
+        for x in [0.2, -0.2] {
+            let ray = Ray::new(Point3::new(x, 0.2, 0.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let hit = scene.world.hit(&ray, 0.001, f32::INFINITY).unwrap();
+            assert!((hit.t - 5.0).abs() < 1e-5);
+            // the mirrored copy keeps facing +z
+            assert!(hit.front_face, "{}", x);
+        }
+
+        let ray = Ray::new(Point3::new(0.0, 3.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = scene.lights.hit(&ray, 0.001, f32::INFINITY).unwrap();
+        let emitted = hit
+            .material
+            .unwrap()
+            .emit(&ray, &hit, hit.u, hit.v, &hit.point);
+        assert!(emitted.x > emitted.y && emitted.y > 0.0);
+
+        assert!(GltfScene::load(dir.join("missing.gltf")).is_err());
+    }
+
+    #[test]
+    fn gltf_base_color_textures() {
+        let hdr: Vec<u8> = [4.0f32, 0.5, 0.25]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let images = [
+            gltf::image::Data {
+                pixels: vec![188, 255, 0],
+                format: Format::R8G8B8,
+                width: 1,
+                height: 1,
+            },
+            gltf::image::Data {
+                pixels: hdr,
+                format: Format::R32G32B32FLOAT,
+                width: 1,
+                height: 1,
+            },
+        ];
+        let mut loader = Loader {
+            buffers: &[],
+            images: &images,
+            materials: HashMap::new(),
+            textures: HashMap::new(),
+            point_lights: Vec::new(),
+            bounds: None,
+            scene: GltfScene {
+                world: World::default(),
+                lights: World::default(),
+                cameras: Vec::new(),
+            },
+        };
+        let point = Point3::default();
+
+        // sRGB 188 is about 0.5 linear, the factor applies to the decoded value
+        let texture = loader.texture(0, Color::new(0.5, 0.5, 1.0)).unwrap();
+        let color = texture.color(0.5, 0.5, &point);
+        assert!((color.x - 0.25).abs() < 0.002, "{:?}", color);
+        assert!(
+            (color.y - 0.5).abs() < 1e-6 && color.z == 0.0,
+            "{:?}",
+            color
+        );
+
+        // float images are linear and not clamped
+        let texture = loader.texture(1, Color::new(1.0, 1.0, 1.0)).unwrap();
+        let color = texture.color(0.5, 0.5, &point);
+        assert_eq!((color.x, color.y, color.z), (4.0, 0.5, 0.25));
+    }
+
+    fn base64_encode(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        bytes
+            .chunks(3)
+            .flat_map(|chunk| {
+                let n = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+                (0..4).map(move |i| {
+                    if i > chunk.len() {
+                        '='
+                    } else {
+                        ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char
+                    }
+                })
+            })
+            .collect()
+    }
+}
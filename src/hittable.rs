@@ -1,6 +1,9 @@
+use std::sync::Arc;
+
 use crate::aabb::AABB;
 use crate::material::ScatterRecord;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::{Point3, Vec3};
 use crate::Material;
 
@@ -13,6 +16,11 @@ pub struct HitRecord<'a> {
     pub u: f32,
     pub v: f32,
     pub front_face: bool,
+    /// Id of the hit object inside the outermost `World`, `0` if the object is not in a `World`.
+    pub object_id: u32,
+    /// Index of the hit primitive inside its object, such as the triangle of a mesh, `0` for
+    /// objects made of a single primitive.
+    pub primitive: u32,
 }
 
 impl<'a> HitRecord<'a> {
@@ -38,63 +46,91 @@ impl<'a> HitRecord<'a> {
             u,
             v,
             front_face,
+            object_id: 0,
+            primitive: 0,
         }
     }
 
-    pub fn scatter(&self, ray: &Ray) -> Option<ScatterRecord> {
-        self.material?.scatter(ray, self)
+    pub fn scatter(&self, ray: &Ray, sampler: &mut dyn Sampler) -> Option<ScatterRecord> {
+        self.material?.scatter(ray, self, sampler)
     }
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
-    fn bounding_box(&self) -> AABB;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
+    /// Box containing the object at every time in `[time0, time1]`.
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB;
     fn pdf_value(&self, _origin: &Point3, _direction: &Vec3) -> f32 {
         0.0
     }
-    fn random(&self, _origin: &Vec3) -> Vec3 {
+    fn random(&self, _origin: &Vec3, _sampler: &mut dyn Sampler) -> Vec3 {
         Vec3::new(1.0, 0.0, 0.0)
     }
 }
 
+impl<T: Hittable + ?Sized> Hittable for Box<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        (**self).hit(ray, t_min, t_max)
+    }
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        (**self).bounding_box(time0, time1)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        (**self).pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        (**self).random(origin, sampler)
+    }
+}
+
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        (**self).hit(ray, t_min, t_max)
+    }
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        (**self).bounding_box(time0, time1)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        (**self).pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        (**self).random(origin, sampler)
+    }
+}
+
 pub struct HittableVTable {
     pub hit: for<'a> fn(&'a (), &Ray, f32, f32) -> Option<HitRecord<'a>>,
-    pub bounding_box: fn(& ()) -> AABB,
-    pub pdf_value: fn(& (), &Point3, &Vec3) -> f32,
-    pub random: fn(&(), &Vec3) -> Vec3,
+    pub bounding_box: fn(&(), f32, f32) -> AABB,
+    pub pdf_value: fn(&(), &Point3, &Vec3) -> f32,
+    pub random: fn(&(), &Vec3, &mut dyn Sampler) -> Vec3,
 }
 
 impl HittableVTable {
+    #[allow(clippy::missing_transmute_annotations)]
     pub fn new<T: Hittable>() -> Self {
         unsafe {
             Self {
                 hit: std::mem::transmute(<T as Hittable>::hit as fn(_, _, _, _) -> _),
                 bounding_box: std::mem::transmute(
-                    <T as Hittable>::bounding_box as fn(_,) -> _,
+                    <T as Hittable>::bounding_box as fn(_, _, _) -> _,
                 ),
                 pdf_value: std::mem::transmute(<T as Hittable>::pdf_value as fn(_, _, _) -> _),
-                random: std::mem::transmute(<T as Hittable>::random as fn(_, _) -> _),
+                random: std::mem::transmute(<T as Hittable>::random as fn(_, _, _) -> _),
             }
         }
     }
 
-    pub fn hit<'a>(
-        &self,
-        ptr: &'a (),
-        ray: &Ray,
-        t_min: f32,
-        t_max: f32,
-    ) -> Option<HitRecord<'a>> {
+    pub fn hit<'a>(&self, ptr: &'a (), ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'a>> {
         (self.hit)(ptr, ray, t_min, t_max)
     }
 
-    pub fn bounding_box(&self, ptr: &()) -> AABB {
-        (self.bounding_box)(ptr)
+    pub fn bounding_box(&self, ptr: &(), time0: f32, time1: f32) -> AABB {
+        (self.bounding_box)(ptr, time0, time1)
     }
     pub fn pdf_value(&self, ptr: &(), origin: &Point3, direction: &Vec3) -> f32 {
         (self.pdf_value)(ptr, origin, direction)
     }
-    pub fn random(&self, ptr: &(), origin: &Vec3) -> Vec3 {
-        (self.random)(ptr, origin)
+    pub fn random(&self, ptr: &(), origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        (self.random)(ptr, origin, sampler)
     }
 }
@@ -1,33 +1,55 @@
 pub use aabb::*;
+pub use adaptive::*;
+pub use aov::*;
 pub use blobvec::*;
 pub use bvh::*;
+pub use bvh4::*;
 pub use camera::*;
+pub use denoise::*;
+pub use gltf_scene::*;
 pub use hittable::*;
 pub use material::*;
+pub use mesh::*;
+pub use obj::*;
 pub use objects::*;
 pub use onb::*;
 pub use pdf::*;
 pub use perlin::*;
+pub use ply::*;
 pub use ray::*;
 pub use renderer::*;
+pub use sampler::*;
+pub use scene::*;
 pub use texture::*;
+pub use tonemap::*;
 pub use transforms::*;
 pub use vec3::*;
 pub use world::*;
 
 pub mod aabb;
+pub mod adaptive;
+pub mod aov;
 pub mod blobvec;
 pub mod bvh;
+pub mod bvh4;
 pub mod camera;
+pub mod denoise;
+pub mod gltf_scene;
 pub mod hittable;
 pub mod material;
+pub mod mesh;
+pub mod obj;
 pub mod objects;
 pub mod onb;
 pub mod pdf;
 pub mod perlin;
+pub mod ply;
 pub mod ray;
 pub mod renderer;
+pub mod sampler;
+pub mod scene;
 pub mod texture;
+pub mod tonemap;
 pub mod transforms;
 pub mod vec3;
 pub mod world;
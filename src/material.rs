@@ -1,9 +1,11 @@
+use std::sync::Arc;
+
 use crate::hittable::HitRecord;
 use crate::pdf::{CosinePdf, Pdf};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::{Color, Point3, Vec3};
 use crate::Texture;
-use rand::Rng;
 
 #[derive(Default)]
 pub struct ScatterRecord {
@@ -14,7 +16,12 @@ pub struct ScatterRecord {
 }
 
 pub trait Material {
-    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Option<ScatterRecord> {
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        _hit_record: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
         None
     }
     fn scattering_pdf(&self, _ray_in: &Ray, _hit_record: &HitRecord, _scattered: &Ray) -> f32 {
@@ -23,6 +30,30 @@ pub trait Material {
     fn emit(&self, _ray: &Ray, _hit: &HitRecord, _u: f32, _v: f32, _point: &Point3) -> Color {
         Color::default()
     }
+    /// Surface color at the hit point, used for the albedo AOV.
+    fn albedo(&self, _hit_record: &HitRecord) -> Color {
+        Color::default()
+    }
+}
+
+impl<M: Material + ?Sized> Material for Arc<M> {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        hit_record: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
+        (**self).scatter(ray_in, hit_record, sampler)
+    }
+    fn scattering_pdf(&self, ray_in: &Ray, hit_record: &HitRecord, scattered: &Ray) -> f32 {
+        (**self).scattering_pdf(ray_in, hit_record, scattered)
+    }
+    fn emit(&self, ray: &Ray, hit: &HitRecord, u: f32, v: f32, point: &Point3) -> Color {
+        (**self).emit(ray, hit, u, v, point)
+    }
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        (**self).albedo(hit_record)
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -37,12 +68,15 @@ impl<T: Texture> Lambertian<T> {
 }
 
 impl<T: Texture> Material for Lambertian<T> {
-    fn scatter(&self, _: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+    fn scatter(
+        &self,
+        _: &Ray,
+        hit_record: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
         Some(ScatterRecord {
             is_specular: false,
-            attenuation: self
-                .albedo
-                .color(hit_record.u, hit_record.v, &hit_record.point),
+            attenuation: self.albedo.hit_color(hit_record),
             pdf: Some(Box::new(CosinePdf::new(&hit_record.normal))),
             ..Default::default()
         })
@@ -55,6 +89,9 @@ impl<T: Texture> Material for Lambertian<T> {
             cosine / std::f32::consts::PI
         }
     }
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.albedo.hit_color(hit_record)
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -73,21 +110,27 @@ impl<T: Texture> Metal<T> {
 }
 
 impl<T: Texture> Material for Metal<T> {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
         let reflected = ray.direction.unit().reflect(&hit_record.normal);
         Some(ScatterRecord {
             specular_ray: Ray::new(
                 hit_record.point,
-                reflected + self.fuzz * Vec3::random_in_unit_sphere(),
+                reflected + self.fuzz * Vec3::random_in_unit_sphere(sampler),
                 ray.time,
             ),
-            attenuation: self
-                .albedo
-                .color(hit_record.u, hit_record.v, &hit_record.point),
+            attenuation: self.albedo.hit_color(hit_record),
             is_specular: true,
             ..Default::default()
         })
     }
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.albedo.hit_color(hit_record)
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -109,7 +152,12 @@ impl Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<ScatterRecord> {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<ScatterRecord> {
         let refraction_ratio = if hit_record.front_face {
             1.0 / self.index_of_refraction
         } else {
@@ -120,7 +168,7 @@ impl Material for Dielectric {
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
 
         let direction = if refraction_ratio * sin_theta > 1.0
-            || Self::reflectance(cos_theta, refraction_ratio) > rand::thread_rng().gen()
+            || Self::reflectance(cos_theta, refraction_ratio) > sampler.get_1d()
         {
             // can not refract
             unit_direction.reflect(&hit_record.normal)
@@ -135,6 +183,9 @@ impl Material for Dielectric {
             ..Default::default()
         })
     }
+    fn albedo(&self, _hit_record: &HitRecord) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -170,6 +221,9 @@ impl<T: Texture> Isotropic<T> {
 }
 
 impl<T: Texture> Material for Isotropic<T> {
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.albedo.hit_color(hit_record)
+    }
     // fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Ray, Color)> {
     //     Some((
     //         Ray::new(hit_record.point, Vec3::random_in_unit_sphere(), ray.time),
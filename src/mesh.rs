@@ -0,0 +1,526 @@
+use std::sync::Arc;
+
+use crate::aabb::AABB;
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::texture::VertexColorTexture;
+use crate::vec3::{Color, Point3, Vec3};
+
+/// Triangles sharing vertex buffers. `normals`, `uvs` and the colors are either empty or hold
+/// one value per position, `indices` index all of them. Hits report the index of the triangle
+/// as `HitRecord::primitive`.
+pub struct TriangleMesh<M: Material> {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub indices: Arc<[[u32; 3]]>,
+    pub material: M,
+    /// Materials of the single triangles used instead of `material` when not empty.
+    face_materials: Vec<M>,
+    /// Either empty or one color per position, shared with the `VertexColorTexture`s.
+    colors: Arc<[Color]>,
+    /// Running sum of the triangle areas, used to pick triangles proportionally to their area.
+    area_cdf: Vec<f32>,
+    aabb: AABB,
+}
+
+impl<M: Material> TriangleMesh<M> {
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f32, f32)>,
+        indices: Vec<[u32; 3]>,
+        material: M,
+    ) -> Result<Self, String> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(format!(
+                "mesh has {} normals for {} positions",
+                normals.len(),
+                positions.len()
+            ));
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            return Err(format!(
+                "mesh has {} uvs for {} positions",
+                uvs.len(),
+                positions.len()
+            ));
+        }
+        if let Some(index) = indices
+            .iter()
+            .flatten()
+            .find(|index| **index as usize >= positions.len())
+        {
+            return Err(format!(
+                "mesh index {} is out of range of {} positions",
+                index,
+                positions.len()
+            ));
+        }
+
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            indices: indices.into(),
+            material,
+            face_materials: Vec::new(),
+            colors: Arc::new([]),
+            area_cdf: Vec::new(),
+            aabb: AABB::default(),
+        };
+        let mut area = 0.0;
+        mesh.area_cdf = (0..mesh.indices.len())
+            .map(|index| {
+                area += mesh.area(index);
+                area
+            })
+            .collect();
+        mesh.aabb = (0..mesh.indices.len())
+            .map(|index| mesh.triangle_bounding_box(index))
+            .reduce(AABB::surrounding_box)
+            .unwrap_or_default();
+        Ok(mesh)
+    }
+
+    /// Gives every triangle its own material, `materials` is either empty or holds one
+    /// material per triangle.
+    pub fn set_face_materials(&mut self, materials: Vec<M>) -> Result<(), String> {
+        if !materials.is_empty() && materials.len() != self.indices.len() {
+            return Err(format!(
+                "mesh has {} face materials for {} triangles",
+                materials.len(),
+                self.indices.len()
+            ));
+        }
+        self.face_materials = materials;
+        Ok(())
+    }
+
+    /// Sets the vertex colors, `colors` is either empty or holds one color per position.
+    pub fn set_colors(&mut self, colors: Vec<Color>) -> Result<(), String> {
+        if !colors.is_empty() && colors.len() != self.positions.len() {
+            return Err(format!(
+                "mesh has {} colors for {} positions",
+                colors.len(),
+                self.positions.len()
+            ));
+        }
+        self.colors = colors.into();
+        Ok(())
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    /// Texture with the vertex colors of the mesh, sharing the colors and indices instead of
+    /// copying them. Only meant for meshes without UVs, see [`VertexColorTexture`].
+    pub fn vertex_color_texture(&self) -> VertexColorTexture {
+        VertexColorTexture::new(self.colors.clone(), self.indices.clone())
+    }
+
+    /// Splits the mesh into triangles that can be added to a `World` or `BVH` one by one.
+    pub fn triangles(mesh: &Arc<Self>) -> Vec<Triangle<M>> {
+        (0..mesh.indices.len())
+            .map(|index| Triangle {
+                mesh: mesh.clone(),
+                index,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Total surface area of the mesh.
+    pub fn total_area(&self) -> f32 {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    fn vertices(&self, index: usize) -> [Point3; 3] {
+        let [i0, i1, i2] = self.indices[index];
+        [
+            self.positions[i0 as usize],
+            self.positions[i1 as usize],
+            self.positions[i2 as usize],
+        ]
+    }
+
+    fn area(&self, index: usize) -> f32 {
+        let [p0, p1, p2] = self.vertices(index);
+        0.5 * (p1 - p0).cross(&(p2 - p0)).length()
+    }
+
+    fn geometric_normal(&self, index: usize) -> Vec3 {
+        let [p0, p1, p2] = self.vertices(index);
+        (p1 - p0).cross(&(p2 - p0)).unit()
+    }
+
+    fn triangle_bounding_box(&self, index: usize) -> AABB {
+        let [p0, p1, p2] = self.vertices(index);
+        // flat triangles get a bit of thickness like the axis aligned rects
+        let padding = Vec3::new(0.00001, 0.00001, 0.00001);
+        AABB::new(
+            Point3::new(
+                p0.x.min(p1.x).min(p2.x),
+                p0.y.min(p1.y).min(p2.y),
+                p0.z.min(p1.z).min(p2.z),
+            ) - padding,
+            Point3::new(
+                p0.x.max(p1.x).max(p2.x),
+                p0.y.max(p1.y).max(p2.y),
+                p0.z.max(p1.z).max(p2.z),
+            ) + padding,
+        )
+    }
+
+    /// Watertight ray-triangle intersection (Woop, Benthin and Wald 2013): rays going
+    /// through an edge shared by two triangles always hit one of them.
+    fn hit_triangle(
+        &self,
+        index: usize,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord<'_>> {
+        let [p0, p1, p2] = self.vertices(index);
+
+        // transform the vertices into a space where the ray starts at the origin
+        // and goes along +z
+        let direction = ray.direction;
+        let kz = if direction.x.abs() > direction.y.abs() {
+            if direction.x.abs() > direction.z.abs() {
+                0
+            } else {
+                2
+            }
+        } else if direction.y.abs() > direction.z.abs() {
+            1
+        } else {
+            2
+        };
+        let kx = if kz == 2 { 0 } else { kz + 1 };
+        let ky = if kx == 2 { 0 } else { kx + 1 };
+        let permute = |v: Vec3| Vec3::new(v[kx], v[ky], v[kz]);
+        let d = permute(direction);
+        let shear_x = -d.x / d.z;
+        let shear_y = -d.y / d.z;
+        let shear_z = 1.0 / d.z;
+        let transform = |p: Point3| {
+            let p = permute(p - ray.origin);
+            Vec3::new(p.x + shear_x * p.z, p.y + shear_y * p.z, p.z)
+        };
+        let (p0t, p1t, p2t) = (transform(p0), transform(p1), transform(p2));
+
+        let mut e0 = p1t.x * p2t.y - p1t.y * p2t.x;
+        let mut e1 = p2t.x * p0t.y - p2t.y * p0t.x;
+        let mut e2 = p0t.x * p1t.y - p0t.y * p1t.x;
+        // fall back to double precision on the edges
+        if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+            let edge =
+                |a: &Vec3, b: &Vec3| (a.x as f64 * b.y as f64 - a.y as f64 * b.x as f64) as f32;
+            e0 = edge(&p1t, &p2t);
+            e1 = edge(&p2t, &p0t);
+            e2 = edge(&p0t, &p1t);
+        }
+        if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+            return None;
+        }
+        let det = e0 + e1 + e2;
+        if det == 0.0 {
+            return None;
+        }
+
+        let t_scaled = (e0 * p0t.z + e1 * p1t.z + e2 * p2t.z) * shear_z;
+        let t = t_scaled / det;
+        if !(t > t_min && t < t_max) {
+            return None;
+        }
+
+        let (b0, b1, b2) = (e0 / det, e1 / det, e2 / det);
+        let point = b0 * p0 + b1 * p1 + b2 * p2;
+        let [i0, i1, i2] = self.indices[index];
+        let (u, v) = if self.uvs.is_empty() {
+            (b1, b2)
+        } else {
+            let (uv0, uv1, uv2) = (
+                self.uvs[i0 as usize],
+                self.uvs[i1 as usize],
+                self.uvs[i2 as usize],
+            );
+            (
+                b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+                b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+            )
+        };
+
+        let geometric_normal = self.geometric_normal(index);
+        let material = self.face_materials.get(index).unwrap_or(&self.material);
+        let mut record = HitRecord::new(point, t, u, v, material, ray, &geometric_normal);
+        record.primitive = index as u32;
+        if !self.normals.is_empty() {
+            let shading_normal = (b0 * self.normals[i0 as usize]
+                + b1 * self.normals[i1 as usize]
+                + b2 * self.normals[i2 as usize])
+                .unit();
+            // keep the shading normal on the side of the surface the ray came from
+            record.normal = if shading_normal.dot(&record.normal) < 0.0 {
+                -shading_normal
+            } else {
+                shading_normal
+            };
+        }
+        Some(record)
+    }
+
+    /// Solid angle density of sampling `direction` by picking a point uniformly on the
+    /// surface of the triangles `range`, summed over all points the direction hits.
+    fn area_pdf_value(
+        &self,
+        range: std::ops::Range<usize>,
+        area: f32,
+        origin: &Point3,
+        direction: &Vec3,
+    ) -> f32 {
+        let ray = Ray::new(*origin, *direction, 0.0);
+        range
+            .filter_map(|index| {
+                self.hit_triangle(index, &ray, 0.0001, f32::INFINITY)
+                    .map(|hit| (index, hit))
+            })
+            .map(|(index, hit)| {
+                let distance_squared = hit.t.powi(2) * direction.length_squared();
+                let cosine =
+                    (direction.dot(&self.geometric_normal(index)) / direction.length()).abs();
+                distance_squared / (cosine * area)
+            })
+            .sum()
+    }
+
+    /// Uniformly distributed point on the triangle `index`.
+    fn random_point(&self, index: usize, sampler: &mut dyn Sampler) -> Point3 {
+        let [p0, p1, p2] = self.vertices(index);
+        let (r1, r2) = sampler.get_2d();
+        let sqrt_r1 = r1.sqrt();
+        (1.0 - sqrt_r1) * p0 + sqrt_r1 * (1.0 - r2) * p1 + sqrt_r1 * r2 * p2
+    }
+}
+
+impl<M: Material> Hittable for TriangleMesh<M> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let mut closest = t_max;
+        let mut last_record = None;
+        for index in 0..self.indices.len() {
+            if let Some(record) = self.hit_triangle(index, ray, t_min, closest) {
+                closest = record.t;
+                last_record = Some(record);
+            }
+        }
+        last_record
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
+        self.aabb
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        self.area_pdf_value(0..self.indices.len(), self.total_area(), origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        if self.indices.is_empty() {
+            return Vec3::new(1.0, 0.0, 0.0);
+        }
+        let target = sampler.get_1d() * self.total_area();
+        let index = self
+            .area_cdf
+            .partition_point(|area| *area <= target)
+            .min(self.indices.len() - 1);
+        self.random_point(index, sampler) - origin
+    }
+}
+
+/// Single triangle of a [`TriangleMesh`].
+pub struct Triangle<M: Material> {
+    pub mesh: Arc<TriangleMesh<M>>,
+    pub index: usize,
+}
+
+impl<M: Material> Triangle<M> {
+    /// Creates a triangle with its own one triangle mesh.
+    pub fn new(p0: Point3, p1: Point3, p2: Point3, material: M) -> Self {
+        let mesh = TriangleMesh::new(
+            vec![p0, p1, p2],
+            Vec::new(),
+            Vec::new(),
+            vec![[0, 1, 2]],
+            material,
+        )
+        .expect("a single triangle mesh is always valid");
+        Self {
+            mesh: Arc::new(mesh),
+            index: 0,
+        }
+    }
+}
+
+impl<M: Material> Hittable for Triangle<M> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.mesh.hit_triangle(self.index, ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
+        self.mesh.triangle_bounding_box(self.index)
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        self.mesh.area_pdf_value(
+            self.index..self.index + 1,
+            self.mesh.area(self.index),
+            origin,
+            direction,
+        )
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.mesh.random_point(self.index, sampler) - origin
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sampler::{RandomSampler, Sampler};
+    use crate::texture::Texture;
+    use crate::{Lambertian, SolidTexture};
+
+    fn quad() -> TriangleMesh<Lambertian<SolidTexture>> {
+        TriangleMesh::new(
+            vec![
+                Point3::new(-1.0, -1.0, 0.0),
+                Point3::new(1.0, -1.0, 0.0),
+                Point3::new(1.0, 1.0, 0.0),
+                Point3::new(-1.0, 1.0, 0.0),
+            ],
+            vec![
+                Vec3::new(-1.0, 0.0, 1.0).unit(),
+                Vec3::new(1.0, 0.0, 1.0).unit(),
+                Vec3::new(1.0, 0.0, 1.0).unit(),
+                Vec3::new(-1.0, 0.0, 1.0).unit(),
+            ],
+            vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+            vec![[0, 1, 2], [0, 2, 3]],
+            Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mesh_watertight() {
+        let mesh = quad();
+        // rays along the shared diagonal and through the shared vertices
+        for i in 0..=100 {
+            let s = -1.0 + i as f32 * 0.02;
+            for origin in [Point3::new(s, s, 1.0), Point3::new(s * 0.999, s, -3.0)] {
+                let direction = Point3::new(s, s, 0.0) - origin;
+                let ray = Ray::new(origin, direction, 0.0);
+                assert!(
+                    mesh.hit(&ray, 0.0001, f32::INFINITY).is_some(),
+                    "{:?}",
+                    origin
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mesh_interpolates_normals_and_uvs() {
+        let mesh = quad();
+        let ray = Ray::new(Point3::new(0.5, -0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = mesh.hit(&ray, 0.0001, f32::INFINITY).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-6);
+        assert!((hit.u - 0.75).abs() < 1e-6 && (hit.v - 0.25).abs() < 1e-6);
+        assert!((hit.normal.x - 0.2_f32.sqrt()).abs() < 1e-5);
+        assert!(hit.normal.x > 0.0 && hit.front_face);
+
+        // from below the normal faces the ray
+        let ray = Ray::new(Point3::new(0.5, -0.5, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = mesh.hit(&ray, 0.0001, f32::INFINITY).unwrap();
+        assert!(hit.normal.z < 0.0 && !hit.front_face);
+    }
+
+    #[test]
+    fn mesh_vertex_colors() {
+        let quad = quad();
+        let mut mesh = TriangleMesh::new(
+            quad.positions.clone(),
+            Vec::new(),
+            Vec::new(),
+            quad.indices.to_vec(),
+            quad.material,
+        )
+        .unwrap();
+        assert!(mesh.set_colors(vec![Color::default(); 3]).is_err());
+        let red = Color::new(1.0, 0.0, 0.0);
+        let blue = Color::new(0.0, 0.0, 1.0);
+        mesh.set_colors(vec![red, red, blue, blue]).unwrap();
+        let texture = mesh.vertex_color_texture();
+
+        // the second triangle on its own reports its index in the mesh
+        let triangles = TriangleMesh::triangles(&Arc::new(mesh));
+        let ray = Ray::new(Point3::new(-0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(triangles[0].hit(&ray, 0.0001, f32::INFINITY).is_none());
+        let hit = triangles[1].hit(&ray, 0.0001, f32::INFINITY).unwrap();
+        assert_eq!(hit.primitive, 1);
+        let color = texture.hit_color(&hit);
+        assert!((color.x - 0.25).abs() < 1e-6 && (color.z - 0.75).abs() < 1e-6);
+        // without colors the texture is white
+        assert_eq!(VertexColorTexture::default().hit_color(&hit).z, 1.0);
+    }
+
+    #[test]
+    fn mesh_pdf_matches_sampling() {
+        let mesh = quad();
+        let origin = Point3::new(0.3, 0.2, 2.0);
+        let mut sampler = RandomSampler::new(3);
+        // integrating the pdf over the sphere of directions gives 1
+        let n = 200_000;
+        let mut integral = 0.0;
+        for i in 0..n {
+            sampler.start_pixel_sample((0, 0), i);
+            let direction = Vec3::random_unit(&mut sampler);
+            integral += mesh.pdf_value(&origin, &direction);
+        }
+        integral *= 4.0 * std::f32::consts::PI / n as f32;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+
+        for i in 0..100 {
+            sampler.start_pixel_sample((1, 0), i);
+            let direction = mesh.random(&origin, &mut sampler);
+            let point = origin + direction;
+            assert!(point.z.abs() < 1e-5 && point.x.abs() <= 1.0 && point.y.abs() <= 1.0);
+            assert!(mesh.pdf_value(&origin, &direction) > 0.0);
+        }
+    }
+
+    #[test]
+    fn mesh_empty() {
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        let mesh =
+            TriangleMesh::new(Vec::new(), Vec::new(), Vec::new(), Vec::new(), material).unwrap();
+        let origin = Point3::new(0.0, 0.0, 1.0);
+        let direction = mesh.random(&origin, &mut RandomSampler::new(1));
+        assert_eq!(mesh.pdf_value(&origin, &direction), 0.0);
+        assert!(mesh
+            .hit(&Ray::new(origin, direction, 0.0), 0.0001, f32::INFINITY)
+            .is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::material::{Dielectric, DiffuseLight, Lambertian, Metal};
+use crate::mesh::TriangleMesh;
+use crate::scene::{SceneMaterial, SceneTexture};
+use crate::texture::{ImageTexture, SolidTexture};
+use crate::vec3::{Color, Point3, Vec3};
+use crate::world::World;
+
+/// Meshes of a Wavefront OBJ file with their MTL materials.
+pub struct ObjModel {
+    pub meshes: Vec<Arc<TriangleMesh<SceneMaterial>>>,
+    /// The meshes with an emissive material, to be sampled as lights.
+    pub lights: Vec<Arc<TriangleMesh<SceneMaterial>>>,
+}
+
+impl ObjModel {
+    /// Loads an OBJ file, the MTL libraries and textures are looked up relative to it.
+    ///
+    /// MTL materials are mapped as follows: an emission `Ke` gives a `DiffuseLight`,
+    /// a dissolve `d` below 1 or a transparent illumination model gives a `Dielectric`
+    /// with the index of refraction `Ni`, a specular color `Ks` brighter than the diffuse
+    /// color `Kd` gives a `Metal` whose fuzz follows the exponent `Ns` and everything else
+    /// is `Lambertian` with `Kd` or the image `map_Kd`. Faces without a material are light grey.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let options = tobj::LoadOptions {
+            single_index: true,
+            triangulate: true,
+            ignore_points: true,
+            ignore_lines: true,
+        };
+        let (models, materials) =
+            tobj::load_obj(path, &options).map_err(|e| format!("{}: {}", path.display(), e))?;
+        // a missing MTL file is not fatal, the faces just get the default material
+        let materials = materials.unwrap_or_default();
+
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut textures = HashMap::new();
+        let mut converted = Vec::with_capacity(materials.len());
+        for material in &materials {
+            converted.push(convert_material(material, base_dir, &mut textures)?);
+        }
+        let default_material: SceneMaterial =
+            Arc::new(Lambertian::new(SolidTexture::from_rgb(0.73, 0.73, 0.73)));
+
+        let mut model = Self {
+            meshes: Vec::with_capacity(models.len()),
+            lights: Vec::new(),
+        };
+        for tobj::Model { mesh, name } in models {
+            if mesh.indices.is_empty() {
+                continue;
+            }
+            let (material, emissive) = match mesh.material_id.and_then(|id| converted.get(id)) {
+                Some((material, emissive)) => (material.clone(), *emissive),
+                None => (default_material.clone(), false),
+            };
+            let positions = mesh
+                .positions
+                .chunks_exact(3)
+                .map(|p| Point3::new(p[0], p[1], p[2]))
+                .collect();
+            let normals = mesh
+                .normals
+                .chunks_exact(3)
+                .map(|n| Vec3::new(n[0], n[1], n[2]).unit())
+                .collect();
+            let uvs = mesh
+                .texcoords
+                .chunks_exact(2)
+                .map(|uv| (uv[0], uv[1]))
+                .collect();
+            let indices = mesh
+                .indices
+                .chunks_exact(3)
+                .map(|i| [i[0], i[1], i[2]])
+                .collect();
+            let mesh = Arc::new(
+                TriangleMesh::new(positions, normals, uvs, indices, material)
+                    .map_err(|e| format!("{}: object `{}`: {}", path.display(), name, e))?,
+            );
+            if emissive {
+                model.lights.push(mesh.clone());
+            }
+            model.meshes.push(mesh);
+        }
+        Ok(model)
+    }
+
+    /// Adds the triangles of all meshes to `world`, lights are also added to `lights`.
+    pub fn add_to(&self, world: &mut World, lights: Option<&mut World>) {
+        for mesh in &self.meshes {
+            for triangle in TriangleMesh::triangles(mesh) {
+                world.add(triangle);
+            }
+        }
+        if let Some(lights) = lights {
+            for light in &self.lights {
+                lights.add(light.clone());
+            }
+        }
+    }
+}
+
+fn color(value: Option<[f32; 3]>) -> Option<Color> {
+    value.map(|[r, g, b]| Color::new(r, g, b))
+}
+
+fn max_component(color: &Color) -> f32 {
+    color.x.max(color.y).max(color.z)
+}
+
+/// Converts a MTL material, returns whether it emits light.
+fn convert_material(
+    material: &tobj::Material,
+    base_dir: &Path,
+    textures: &mut HashMap<String, SceneTexture>,
+) -> Result<(SceneMaterial, bool), String> {
+    let emission = color(material.emissive).unwrap_or_default();
+    if max_component(&emission) > 0.0 {
+        return Ok((
+            Arc::new(DiffuseLight::new(SolidTexture::from_color(emission))),
+            true,
+        ));
+    }
+
+    let transparent = material.dissolve.is_some_and(|d| d < 1.0)
+        || matches!(material.illumination_model, Some(4 | 6 | 7 | 9));
+    if transparent {
+        let index_of_refraction = material.optical_density.unwrap_or(1.5);
+        return Ok((Arc::new(Dielectric::new(index_of_refraction)), false));
+    }
+
+    let diffuse = color(material.diffuse).unwrap_or_else(|| Color::new(0.73, 0.73, 0.73));
+    let specular = color(material.specular).unwrap_or_default();
+    if material.diffuse_texture.is_none() && max_component(&specular) > max_component(&diffuse) {
+        // glossier materials have a higher specular exponent
+        let fuzz = (2.0 / (material.shininess.unwrap_or(0.0).max(0.0) + 2.0)).sqrt();
+        return Ok((
+            Arc::new(Metal::new(SolidTexture::from_color(specular), fuzz)),
+            false,
+        ));
+    }
+
+    let albedo: SceneTexture = match &material.diffuse_texture {
+        Some(file) => match textures.get(file) {
+            Some(texture) => texture.clone(),
+            None => {
+                let path = base_dir.join(file);
+                let texture: SceneTexture =
+                    Arc::new(ImageTexture::new(&path.to_string_lossy()).map_err(|e| {
+                        format!("material `{}`: {}: {}", material.name, path.display(), e)
+                    })?);
+                textures.insert(file.clone(), texture.clone());
+                texture
+            }
+        },
+        None => Arc::new(SolidTexture::from_color(diffuse)),
+    };
+    Ok((Arc::new(Lambertian::new(albedo)), false))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hittable::Hittable;
+    use crate::ray::Ray;
+
+    #[test]
+    fn obj_load_with_materials() {
+        let dir = std::env::temp_dir().join("rust_raytracing_obj_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("quads.mtl"),
+            "newmtl white\nKd 0.8 0.8 0.8\n\n\
+             newmtl lamp\nKd 0 0 0\nKe 4 4 4\n\n\
+             newmtl glass\nNi 1.45\nd 0.1\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("quads.obj"),
+            "mtllib quads.mtl\n\
+             v -1 -1 0\nv 1 -1 0\nv 1 1 0\nv -1 1 0\n\
+             v -1 -1 2\nv 1 -1 2\nv 1 1 2\nv -1 1 2\n\
+             o floor\nusemtl white\nf 1 2 3 4\n\
+             o lamp\nusemtl lamp\nf 8 7 6 5\n",
+        )
+        .unwrap();
+
+        let model = ObjModel::load(dir.join("quads.obj")).unwrap();
+        assert_eq!(model.meshes.len(), 2);
+        assert_eq!(model.meshes[0].len(), 2);
+        assert_eq!(model.lights.len(), 1);
+
+        let mut world = World::default();
+        let mut lights = World::default();
+        model.add_to(&mut world, Some(&mut lights));
+        let ray = Ray::new(Point3::new(0.2, 0.3, 1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = world.hit(&ray, 0.001, f32::INFINITY).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-5);
+        let emitted = hit
+            .material
+            .unwrap()
+            .emit(&ray, &hit, hit.u, hit.v, &hit.point);
+        assert_eq!(emitted.x, 4.0);
+        assert!(lights.hit(&ray, 0.001, f32::INFINITY).is_some());
+
+        assert!(ObjModel::load(dir.join("missing.obj")).is_err());
+    }
+}
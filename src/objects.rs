@@ -2,9 +2,9 @@ use crate::aabb::AABB;
 use crate::hittable::{HitRecord, Hittable};
 use crate::onb::Onb;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::{Point3, Vec3};
 use crate::Material;
-use rand::Rng;
 use std::borrow::Borrow;
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -38,7 +38,7 @@ impl<M: Material> Sphere<M> {
 }
 
 impl<M: Material> Hittable for Sphere<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let os = ray.origin - self.center;
         let a = ray.direction.length_squared();
         let half_b = os.dot(&ray.direction);
@@ -72,7 +72,7 @@ impl<M: Material> Hittable for Sphere<M> {
         ))
     }
 
-    fn bounding_box(&self) -> AABB {
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
         AABB::new(
             self.center - Vec3::new(self.radius, self.radius, self.radius),
             self.center + Vec3::new(self.radius, self.radius, self.radius),
@@ -93,10 +93,14 @@ impl<M: Material> Hittable for Sphere<M> {
         }
     }
 
-    fn random(&self, origin: &Vec3) -> Vec3 {
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
         let dir = self.center - origin;
         let uvw = Onb::new_from_w(&dir);
-        uvw.local_from_vec(&Vec3::random_to_sphere(self.radius, dir.length_squared()))
+        uvw.local_from_vec(&Vec3::random_to_sphere(
+            self.radius,
+            dir.length_squared(),
+            sampler,
+        ))
     }
 }
 
@@ -136,7 +140,7 @@ impl<M: Material> MovingSphere<M> {
 }
 
 impl<M: Material> Hittable for MovingSphere<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let os = ray.origin - self.center(ray.time);
         let a = ray.direction.length_squared();
         let half_b = os.dot(&ray.direction);
@@ -170,12 +174,11 @@ impl<M: Material> Hittable for MovingSphere<M> {
         ))
     }
 
-    fn bounding_box(&self) -> AABB {
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        // the center moves on a line, so the boxes at both ends contain every box in between
         let r = Vec3::new(self.radius, self.radius, self.radius);
-        // let box0 = AABB::new(self.center(time0) - r, self.center(time0) + r);
-        // let box1 = AABB::new(self.center(time1) - r, self.center(time1) + r);
-        let box0 = AABB::new(self.center0 - r, self.center0 + r);
-        let box1 = AABB::new(self.center1 - r, self.center1 + r);
+        let box0 = AABB::new(self.center(time0) - r, self.center(time0) + r);
+        let box1 = AABB::new(self.center(time1) - r, self.center(time1) + r);
         AABB::surrounding_box(box0, box1)
     }
 }
@@ -204,7 +207,7 @@ impl<M: Material> XYRect<M> {
 }
 
 impl<M: Material> Hittable for XYRect<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let t = (self.k - ray.origin.z) / ray.direction.z;
         if t < t_min || t > t_max {
             return None;
@@ -227,7 +230,7 @@ impl<M: Material> Hittable for XYRect<M> {
         ))
     }
 
-    fn bounding_box(&self) -> AABB {
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
         AABB::new(
             Point3::new(self.x0, self.y0, self.k - 0.00001),
             Point3::new(self.x1, self.y1, self.k + 0.00001),
@@ -259,7 +262,7 @@ impl<M: Material> XZRect<M> {
 }
 
 impl<M: Material> Hittable for XZRect<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let t = (self.k - ray.origin.y) / ray.direction.y;
         if t < t_min || t > t_max {
             return None;
@@ -282,7 +285,7 @@ impl<M: Material> Hittable for XZRect<M> {
         ))
     }
 
-    fn bounding_box(&self) -> AABB {
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
         AABB::new(
             Point3::new(self.x0, self.k - 0.00001, self.z0),
             Point3::new(self.x1, self.k + 0.00001, self.z1),
@@ -299,11 +302,12 @@ impl<M: Material> Hittable for XZRect<M> {
             0.0
         }
     }
-    fn random(&self, origin: &Vec3) -> Vec3 {
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (x, z) = sampler.get_2d();
         let random_point = Point3::new(
-            rand::thread_rng().gen_range(self.x0..self.x1),
+            self.x0 + x * (self.x1 - self.x0),
             self.k,
-            rand::thread_rng().gen_range(self.z0..self.z1),
+            self.z0 + z * (self.z1 - self.z0),
         );
         random_point - origin
     }
@@ -333,7 +337,7 @@ impl<M: Material> YZRect<M> {
 }
 
 impl<M: Material> Hittable for YZRect<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let t = (self.k - ray.origin.x) / ray.direction.x;
         if t < t_min || t > t_max {
             return None;
@@ -356,7 +360,7 @@ impl<M: Material> Hittable for YZRect<M> {
         ))
     }
 
-    fn bounding_box(&self) -> AABB {
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
         AABB::new(
             Point3::new(self.k - 0.00001, self.y0, self.z0),
             Point3::new(self.k + 0.00001, self.y1, self.z1),
@@ -376,25 +380,25 @@ pub struct Box3d<M: Material> {
     pub yz_max: YZRect<M>,
 }
 
-impl<M: Material + Copy> Box3d<M> {
+impl<M: Material + Clone> Box3d<M> {
     pub fn new(min: Point3, max: Point3, material: M) -> Self {
         Self {
             min,
             max,
-            xy_min: XYRect::new(min.x, max.x, min.y, max.y, max.z, material),
-            xy_max: XYRect::new(min.x, max.x, min.y, max.y, min.z, material),
+            xy_min: XYRect::new(min.x, max.x, min.y, max.y, max.z, material.clone()),
+            xy_max: XYRect::new(min.x, max.x, min.y, max.y, min.z, material.clone()),
 
-            xz_min: XZRect::new(min.x, max.x, min.z, max.z, min.y, material),
-            xz_max: XZRect::new(min.x, max.x, min.z, max.z, max.y, material),
+            xz_min: XZRect::new(min.x, max.x, min.z, max.z, min.y, material.clone()),
+            xz_max: XZRect::new(min.x, max.x, min.z, max.z, max.y, material.clone()),
 
-            yz_min: YZRect::new(min.y, max.y, min.z, max.z, min.x, material),
+            yz_min: YZRect::new(min.y, max.y, min.z, max.z, min.x, material.clone()),
             yz_max: YZRect::new(min.y, max.y, min.z, max.z, max.x, material),
         }
     }
 }
 
 impl<M: Material> Hittable for Box3d<M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let mut last_record = HitRecord::default();
         let mut hit_anything = false;
         let mut closest = t_max;
@@ -425,7 +429,6 @@ impl<M: Material> Hittable for Box3d<M> {
         }
         if let Some(record) = self.yz_max.hit(ray, t_min, closest) {
             hit_anything = true;
-            closest = record.t;
             last_record = record;
         }
         if hit_anything {
@@ -435,7 +438,7 @@ impl<M: Material> Hittable for Box3d<M> {
         }
     }
 
-    fn bounding_box(&self) -> AABB {
+    fn bounding_box(&self, _time0: f32, _time1: f32) -> AABB {
         AABB::new(self.min, self.max)
     }
 }
@@ -1,12 +1,11 @@
-use rand::Rng;
-
 use crate::onb::Onb;
+use crate::sampler::Sampler;
 use crate::vec3::{Point3, Vec3};
 use crate::Hittable;
 
 pub trait Pdf {
     fn value(&self, direction: &Vec3) -> f32;
-    fn generate(&self) -> Vec3;
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3;
 }
 
 pub struct CosinePdf {
@@ -31,8 +30,9 @@ impl Pdf for CosinePdf {
         }
     }
 
-    fn generate(&self) -> Vec3 {
-        self.uvw.local_from_vec(&Vec3::random_cosine_direction())
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        self.uvw
+            .local_from_vec(&Vec3::random_cosine_direction(sampler))
     }
 }
 
@@ -61,8 +61,8 @@ where
         self.object.pdf_value(&self.origin, direction)
     }
 
-    fn generate(&self) -> Vec3 {
-        self.object.random(&self.origin)
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        self.object.random(&self.origin, sampler)
     }
 }
 
@@ -82,11 +82,11 @@ impl<'a> Pdf for MixturePdf<'a> {
         0.5 * self.pdf1.value(direction) + 0.5 * self.pdf2.value(direction)
     }
 
-    fn generate(&self) -> Vec3 {
-        if rand::thread_rng().gen_bool(0.5) {
-            self.pdf1.generate()
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        if sampler.get_1d() < 0.5 {
+            self.pdf1.generate(sampler)
         } else {
-            self.pdf2.generate()
+            self.pdf2.generate(sampler)
         }
     }
 }
@@ -1,3 +1,4 @@
+use crate::sampler::Pcg32;
 use crate::vec3::{Point3, Vec3};
 
 const PERLIN_POINT_COUNT: u32 = 256;
@@ -9,20 +10,21 @@ pub struct Perlin {
     perm_z: Vec<u32>,
 }
 
-impl Default for Perlin {
-    fn default() -> Self {
+impl Perlin {
+    /// Noise with gradients and permutations generated from `seed`.
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Pcg32::new(seed);
+        let random = |rng: &mut Pcg32| rng.next_f32() * 2.0 - 1.0;
         Self {
             random_vec: (0..PERLIN_POINT_COUNT)
-                .map(|_| Vec3::random(-1.0, 1.0).unit())
+                .map(|_| Vec3::new(random(&mut rng), random(&mut rng), random(&mut rng)).unit())
                 .collect::<Vec<_>>(),
-            perm_x: Self::perlin_generate_perm(),
-            perm_y: Self::perlin_generate_perm(),
-            perm_z: Self::perlin_generate_perm(),
+            perm_x: Self::perlin_generate_perm(&mut rng),
+            perm_y: Self::perlin_generate_perm(&mut rng),
+            perm_z: Self::perlin_generate_perm(&mut rng),
         }
     }
-}
 
-impl Perlin {
     pub fn noise(&self, point: &Point3) -> f32 {
         let u = point.x - point.x.floor();
         let v = point.y - point.y.floor();
@@ -59,14 +61,10 @@ impl Perlin {
         accum.abs()
     }
 
-    fn perlin_generate_perm() -> Vec<u32> {
+    fn perlin_generate_perm(rng: &mut Pcg32) -> Vec<u32> {
         let mut p = (0..PERLIN_POINT_COUNT).collect::<Vec<u32>>();
-
-        use rand::distributions::Distribution;
-        let mut rng = rand::thread_rng();
         for i in (1..PERLIN_POINT_COUNT).rev() {
-            let uniform = rand::distributions::Uniform::new(0, i);
-            let target = uniform.sample(&mut rng);
+            let target = rng.next_u32() % i;
             p.swap(i as usize, target as usize);
         }
         p
@@ -0,0 +1,459 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::material::Material;
+use crate::mesh::TriangleMesh;
+use crate::texture::VertexColorTexture;
+use crate::vec3::{Color, Point3, Vec3};
+
+/// Most entries reserved up front, larger elements grow while they are read so a corrupt
+/// header can not allocate more than the file holds.
+const MAX_RESERVE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(format!("unknown property type `{}`", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    /// Scale mapping the full range of integer colors onto `[0, 1]`.
+    fn color_scale(self) -> f32 {
+        match self {
+            Self::U8 => 1.0 / 255.0,
+            Self::U16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    /// A list with the type of its length and of its items.
+    List(String, Scalar, Scalar),
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Self::Scalar(name, _) | Self::List(name, _, _) => name,
+        }
+    }
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Reads the values of the body one element entry at a time.
+struct Body<R: BufRead> {
+    reader: R,
+    format: Format,
+    /// Values of the current line of an ASCII file.
+    values: Vec<f64>,
+    position: usize,
+    line: String,
+}
+
+impl<R: BufRead> Body<R> {
+    fn start_entry(&mut self) -> Result<(), String> {
+        if self.format != Format::Ascii {
+            return Ok(());
+        }
+        self.line.clear();
+        self.values.clear();
+        self.position = 0;
+        while self.line.trim().is_empty() {
+            self.line.clear();
+            if self
+                .reader
+                .read_line(&mut self.line)
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("unexpected end of file".to_string());
+            }
+        }
+        for token in self.line.split_whitespace() {
+            self.values.push(
+                token
+                    .parse()
+                    .map_err(|_| format!("invalid number `{}`", token))?,
+            );
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, scalar: Scalar) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            let value = self
+                .values
+                .get(self.position)
+                .copied()
+                .ok_or("too few values on a line")?;
+            self.position += 1;
+            return Ok(value);
+        }
+
+        let mut bytes = [0; 8];
+        let bytes = &mut bytes[..scalar.size()];
+        self.reader
+            .read_exact(bytes)
+            .map_err(|_| "unexpected end of file".to_string())?;
+        if self.format == Format::BinaryBigEndian {
+            bytes.reverse();
+        }
+        Ok(match scalar {
+            Scalar::I8 => bytes[0] as i8 as f64,
+            Scalar::U8 => bytes[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ]),
+        })
+    }
+
+    /// Reads all values of one entry of `element` into `row`, lists are expanded in place.
+    fn entry(&mut self, element: &Element, row: &mut Vec<f64>) -> Result<(), String> {
+        self.start_entry()?;
+        row.clear();
+        for property in &element.properties {
+            match property {
+                Property::Scalar(_, scalar) => row.push(self.value(*scalar)?),
+                Property::List(_, count, item) => {
+                    let count = self.value(*count)?;
+                    if count < 0.0 || count.fract() != 0.0 {
+                        return Err(format!("invalid list length {}", count));
+                    }
+                    row.push(count);
+                    for _ in 0..count as usize {
+                        row.push(self.value(*item)?);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads a triangle mesh from an ASCII or binary PLY file. Polygons are split into triangle
+/// fans and vertex normals are used when present. `material` makes the material of the mesh
+/// from a `VertexColorTexture` with the vertex colors, white without them.
+pub fn load_ply<M, P, F>(path: P, material: F) -> Result<TriangleMesh<M>, String>
+where
+    M: Material,
+    P: AsRef<Path>,
+    F: Fn(VertexColorTexture) -> M,
+{
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    read_ply(BufReader::new(file), material).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read_ply<M, R, F>(mut reader: R, material: F) -> Result<TriangleMesh<M>, String>
+where
+    M: Material,
+    R: BufRead,
+    F: Fn(VertexColorTexture) -> M,
+{
+    let (format, elements) = read_header(&mut reader)?;
+    let mut body = Body {
+        reader,
+        format,
+        values: Vec::new(),
+        position: 0,
+        line: String::new(),
+    };
+
+    let mut positions: Vec<Point3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut colors: Vec<Color> = Vec::new();
+    let mut indices = Vec::new();
+    let mut row = Vec::new();
+    let vertex_count = elements
+        .iter()
+        .find(|element| element.name == "vertex")
+        .map_or(0, |element| element.count);
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                // offsets of the properties inside a row, lists in vertices are unusual
+                // enough to not bother
+                if element
+                    .properties
+                    .iter()
+                    .any(|property| matches!(property, Property::List(..)))
+                {
+                    return Err("list properties in vertices are not supported".to_string());
+                }
+                let find = |name: &str| {
+                    element
+                        .properties
+                        .iter()
+                        .position(|property| property.name() == name)
+                };
+                let find_all =
+                    |names: [&str; 3]| Some([find(names[0])?, find(names[1])?, find(names[2])?]);
+                let position =
+                    find_all(["x", "y", "z"]).ok_or("vertices are missing a coordinate")?;
+                let normal = find_all(["nx", "ny", "nz"]);
+                let color = find_all(["red", "green", "blue"]);
+                let color_scale = color.map_or(1.0, |[red, _, _]| match &element.properties[red] {
+                    Property::Scalar(_, scalar) => scalar.color_scale(),
+                    Property::List(..) => 1.0,
+                });
+
+                let reserve = element.count.min(MAX_RESERVE);
+                positions.reserve_exact(reserve);
+                if normal.is_some() {
+                    normals.reserve_exact(reserve);
+                }
+                if color.is_some() {
+                    colors.reserve_exact(reserve);
+                }
+                let vec3 = |row: &[f64], [x, y, z]: [usize; 3]| {
+                    Vec3::new(row[x] as f32, row[y] as f32, row[z] as f32)
+                };
+                for vertex in 0..element.count {
+                    body.entry(element, &mut row)
+                        .map_err(|e| format!("vertex {}: {}", vertex, e))?;
+                    positions.push(vec3(&row, position));
+                    if let Some(normal) = normal {
+                        normals.push(vec3(&row, normal).unit());
+                    }
+                    if let Some(color) = color {
+                        colors.push(color_scale * vec3(&row, color));
+                    }
+                }
+            }
+            "face" => {
+                let list = element
+                    .properties
+                    .iter()
+                    .position(|property| {
+                        matches!(property, Property::List(name, _, _)
+                            if name == "vertex_indices" || name == "vertex_index")
+                    })
+                    .ok_or("faces are missing the vertex_indices list")?;
+                indices.reserve_exact(element.count.min(MAX_RESERVE));
+                for face in 0..element.count {
+                    body.entry(element, &mut row)
+                        .map_err(|e| format!("face {}: {}", face, e))?;
+                    // skip the values of the properties before the list
+                    let start = element.properties[..list]
+                        .iter()
+                        .try_fold(0, |start, property| match property {
+                            Property::Scalar(..) => Some(start + 1),
+                            Property::List(..) => Some(start + 1 + *row.get(start)? as usize),
+                        })
+                        .ok_or_else(|| format!("face {}: truncated", face))?;
+                    let count = row[start] as usize;
+                    let vertices = row[start + 1..start + 1 + count]
+                        .iter()
+                        .map(|&index| vertex_index(index, vertex_count))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| format!("face {}: {}", face, e))?;
+                    for i in 1..count.saturating_sub(1) {
+                        indices.push([vertices[0], vertices[i], vertices[i + 1]]);
+                    }
+                }
+            }
+            _ => {
+                for entry in 0..element.count {
+                    body.entry(element, &mut row)
+                        .map_err(|e| format!("{} {}: {}", element.name, entry, e))?;
+                }
+            }
+        }
+    }
+
+    let white = material(VertexColorTexture::default());
+    let mut mesh = TriangleMesh::new(positions, normals, Vec::new(), indices, white)?;
+    if !colors.is_empty() {
+        mesh.set_colors(colors)?;
+        mesh.material = material(mesh.vertex_color_texture());
+    }
+    Ok(mesh)
+}
+
+/// Checks that a vertex index read from a face is a whole number below `vertex_count`.
+fn vertex_index(index: f64, vertex_count: usize) -> Result<u32, String> {
+    if index < 0.0 || index.fract() != 0.0 || index >= vertex_count as f64 {
+        return Err(format!(
+            "invalid vertex index {} for {} vertices",
+            index, vertex_count
+        ));
+    }
+    Ok(index as u32)
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<(Format, Vec<Element>), String> {
+    let mut line = String::new();
+    let mut next_line = |line: &mut String| -> Result<(), String> {
+        line.clear();
+        if reader.read_line(line).map_err(|e| e.to_string())? == 0 {
+            return Err("unexpected end of the header".to_string());
+        }
+        Ok(())
+    };
+
+    next_line(&mut line)?;
+    if line.trim_end() != "ply" {
+        return Err("not a PLY file".to_string());
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    loop {
+        next_line(&mut line)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["end_header"] => break,
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", name, _version] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(format!("unknown format `{}`", name)),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("invalid element count `{}`", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .ok_or("property outside of an element")?
+                .properties
+                .push(Property::List(
+                    name.to_string(),
+                    Scalar::parse(count)?,
+                    Scalar::parse(item)?,
+                )),
+            ["property", scalar, name] => elements
+                .last_mut()
+                .ok_or("property outside of an element")?
+                .properties
+                .push(Property::Scalar(name.to_string(), Scalar::parse(scalar)?)),
+            _ => return Err(format!("invalid header line `{}`", line.trim_end())),
+        }
+    }
+    Ok((format.ok_or("the header has no format")?, elements))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hittable::Hittable;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+
+    #[test]
+    fn ply_ascii_and_binary() {
+        let ascii = "ply\nformat ascii 1.0\ncomment a colored quad\n\
+            element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0 255 0 0\n1 0 0 255 0 0\n1 1 0 0 0 255\n0 1 0 0 0 255\n\
+            4 0 1 2 3\n";
+
+        let mut binary = ascii.split("end_header\n").next().unwrap().to_string();
+        binary = binary.replace("ascii", "binary_little_endian") + "end_header\n";
+        let mut binary = binary.into_bytes();
+        let vertices = [
+            (0.0f32, 0.0f32, [255, 0, 0]),
+            (1.0, 0.0, [255, 0, 0]),
+            (1.0, 1.0, [0, 0, 255]),
+            (0.0, 1.0, [0, 0, 255]),
+        ];
+        for &(x, y, color) in &vertices {
+            for v in &[x, y, 0.0] {
+                binary.extend_from_slice(&v.to_le_bytes());
+            }
+            binary.extend_from_slice(&color);
+        }
+        binary.push(4);
+        for i in 0..4i32 {
+            binary.extend_from_slice(&i.to_le_bytes());
+        }
+
+        for source in &[ascii.as_bytes(), &binary] {
+            let mesh = read_ply(*source, Lambertian::new).unwrap();
+            assert_eq!(mesh.len(), 2);
+            assert_eq!(mesh.colors().len(), 4);
+            let ray = Ray::new(Point3::new(0.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let hit = mesh.hit(&ray, 0.001, f32::INFINITY).unwrap();
+            let color = hit.material.unwrap().albedo(&hit);
+            assert!((color.x - 0.75).abs() < 1e-5 && (color.z - 0.25).abs() < 1e-5);
+        }
+
+        assert!(read_ply(&binary[..binary.len() - 2], Lambertian::new).is_err());
+        for face in &["3 0 1 7", "3 0 -1 2", "3 0 1.5 2", "-3 0 1 2"] {
+            let invalid = ascii.replace("4 0 1 2 3", face);
+            assert!(read_ply(invalid.as_bytes(), Lambertian::new).is_err());
+        }
+        // a count far beyond the data fails at the end of the file instead of allocating
+        let huge = ascii.replace("element face 1", "element face 4000000000");
+        assert!(read_ply(huge.as_bytes(), Lambertian::new).is_err());
+
+        // without colors the mesh has a single white material
+        let uncolored = ascii
+            .replace(
+                "property uchar red\nproperty uchar green\nproperty uchar blue\n",
+                "",
+            )
+            .replace(" 255 0 0\n", "\n")
+            .replace(" 0 0 255\n", "\n");
+        let mesh = read_ply(uncolored.as_bytes(), Lambertian::new).unwrap();
+        assert!(mesh.colors().is_empty());
+        let ray = Ray::new(Point3::new(0.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = mesh.hit(&ray, 0.001, f32::INFINITY).unwrap();
+        assert_eq!(hit.material.unwrap().albedo(&hit).x, 1.0);
+    }
+}
@@ -1,24 +1,91 @@
 use std::borrow::Borrow;
+use std::io::Write;
+use std::path::Path;
 
-use rand::distributions::Distribution;
+use rayon::prelude::*;
+#[cfg(feature = "sdl")]
 use sdl2::event::Event;
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::Keycode;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::PixelFormatEnum;
 
+use crate::adaptive::{heat_map, AdaptiveSampling};
+use crate::aov::{visualize_aov, Aov, AovSample};
 use crate::camera::Camera;
+use crate::denoise::{DenoiseGuides, Denoiser};
 use crate::pdf::{HittablePdf, MixturePdf, Pdf};
 use crate::ray::Ray;
+use crate::sampler::{Sampler, SamplerType};
+use crate::tonemap::{luminance, DisplayTransform};
 use crate::vec3::Color;
 use crate::Hittable;
 
+pub use image::ImageOutputFormat;
+
+/// Side of the square tiles the image is split into for rendering.
+const TILE_SIZE: u32 = 16;
+
+/// Rectangle of the image in pixels, rows go from top to bottom.
+#[derive(Debug, Clone, Copy)]
+struct Tile {
+    x0: u32,
+    row0: u32,
+    x1: u32,
+    row1: u32,
+}
+
+/// Sums of the new samples of every pixel of a tile, stored row by row from top to bottom.
+struct TileSamples {
+    colors: Vec<Color>,
+    /// Squared luminance of the samples, used to estimate the variance.
+    squares: Vec<f32>,
+    /// Empty when AOVs are disabled.
+    aovs: Vec<AovSample>,
+    /// Number of rays traced.
+    rays: u64,
+}
+
+/// Statistics of the work done since the last [`Renderer::clear`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RenderStats {
+    /// Camera samples, one primary ray each.
+    pub samples: u64,
+    /// All rays traced, primary and scattered.
+    pub rays: u64,
+    pub render_time: std::time::Duration,
+}
+
+impl RenderStats {
+    /// Traced rays per second.
+    pub fn rays_per_second(&self) -> f64 {
+        self.rays as f64 / self.render_time.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
 pub struct Renderer {
     screen_width: u32,
     screen_height: u32,
     samples_per_pixel: u32,
     max_depth: u32,
     background: Color,
-    pitch: u32,
     buffer: Vec<u8>,
+    hdr_buffer: Vec<Color>,
+    accumulation: Vec<Color>,
+    /// Sum of the squared luminance of all samples of every pixel.
+    squares: Vec<f32>,
+    sample_counts: Vec<u32>,
+    samples_taken: u32,
+    seed: u64,
+    sampler_type: SamplerType,
+    display_transform: DisplayTransform,
+    thread_pool: rayon::ThreadPool,
+    /// Summed first hit values of every pixel, empty when AOVs are disabled.
+    aovs: Vec<AovSample>,
+    aovs_enabled: bool,
+    denoiser: Option<Denoiser>,
+    adaptive_sampling: Option<AdaptiveSampling>,
+    stats: RenderStats,
 }
 
 impl<'a> Renderer {
@@ -35,11 +102,116 @@ impl<'a> Renderer {
             samples_per_pixel,
             max_depth,
             background,
-            pitch: screen_width * 3,
             buffer: vec![0u8; (screen_width * screen_height * 3) as usize],
+            hdr_buffer: vec![Color::default(); (screen_width * screen_height) as usize],
+            accumulation: vec![Color::default(); (screen_width * screen_height) as usize],
+            squares: vec![0.0; (screen_width * screen_height) as usize],
+            sample_counts: vec![0; (screen_width * screen_height) as usize],
+            samples_taken: 0,
+            seed: 0,
+            sampler_type: SamplerType::default(),
+            display_transform: DisplayTransform::default(),
+            thread_pool: Self::create_thread_pool(0)?,
+            aovs: Vec::new(),
+            aovs_enabled: false,
+            denoiser: None,
+            adaptive_sampling: None,
+            stats: RenderStats::default(),
         })
     }
 
+    /// Sets the seed all sample values are derived from. Renders with the same seed, scene and
+    /// settings produce identical images regardless of the number of threads.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Selects the sampler producing the pixel, lens, time, material and light samples.
+    pub fn set_sampler(&mut self, sampler_type: SamplerType) {
+        self.sampler_type = sampler_type;
+    }
+
+    pub fn sampler(&self) -> SamplerType {
+        self.sampler_type
+    }
+
+    /// Sets the number of threads used for rendering, `0` uses all available cores.
+    pub fn set_threads(&mut self, threads: usize) -> Result<(), String> {
+        self.thread_pool = Self::create_thread_pool(threads)?;
+        Ok(())
+    }
+
+    /// Renders on `thread_pool`, e.g. the one the scene was built on.
+    pub fn set_thread_pool(&mut self, thread_pool: rayon::ThreadPool) {
+        self.thread_pool = thread_pool;
+    }
+
+    /// Number of threads used for rendering.
+    pub fn threads(&self) -> usize {
+        self.thread_pool.current_num_threads()
+    }
+
+    /// Runs `f` on the rendering threads, such as building a [`BVH`](crate::BVH) in parallel
+    /// with the same threads as the render.
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        self.thread_pool.install(f)
+    }
+
+    fn create_thread_pool(threads: usize) -> Result<rayon::ThreadPool, String> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Enables recording of the AOVs (depth, normal, albedo, ...) at the first hit of the
+    /// camera rays. Takes effect for the samples rendered after the next [`Renderer::clear`].
+    pub fn set_aovs(&mut self, enabled: bool) {
+        self.aovs_enabled = enabled;
+    }
+
+    pub fn aovs_enabled(&self) -> bool {
+        self.aovs_enabled
+    }
+
+    /// Sets the denoiser applied to the averaged samples after every render (pass).
+    /// The denoiser is guided by the AOVs, so they are recorded whenever a denoiser is set.
+    /// Like [`Renderer::set_aovs`] enabling it takes effect after the next [`Renderer::clear`].
+    pub fn set_denoiser(&mut self, denoiser: Option<Denoiser>) {
+        self.denoiser = denoiser;
+        if self.samples_taken != 0 {
+            self.resolve();
+        }
+    }
+
+    pub fn denoiser(&self) -> Option<&Denoiser> {
+        self.denoiser.as_ref()
+    }
+
+    /// Enables adaptive sampling. [`Renderer::render`] then spends the same total budget of
+    /// `samples_per_pixel` samples per pixel on average, but stops sampling pixels that
+    /// converged and gives their samples to the noisy ones. [`Renderer::render_pass`] only
+    /// adds samples to pixels that did not converge yet.
+    pub fn set_adaptive_sampling(&mut self, adaptive_sampling: Option<AdaptiveSampling>) {
+        self.adaptive_sampling = adaptive_sampling;
+    }
+
+    pub fn adaptive_sampling(&self) -> Option<&AdaptiveSampling> {
+        self.adaptive_sampling.as_ref()
+    }
+
+    /// Sets the transform used to turn the linear radiance into the 8-bit display image
+    /// and re-encodes the already rendered image with it.
+    pub fn set_display_transform(&mut self, display_transform: DisplayTransform) {
+        self.display_transform = display_transform;
+        self.update_display();
+    }
+
+    /// Renders the image with `samples_per_pixel` samples, discarding any previous result.
     pub fn render<H, L>(
         &mut self,
         hittable: &H,
@@ -50,48 +222,428 @@ impl<'a> Renderer {
         H: Hittable + Sync,
         L: Hittable + Sync,
     {
-        let now = std::time::Instant::now();
+        self.clear();
+        match self.adaptive_sampling {
+            Some(adaptive_sampling) => {
+                self.render_adaptive(hittable, camera, lights, &adaptive_sampling)
+            }
+            None => {
+                let samples = vec![self.samples_per_pixel; self.sample_counts.len()];
+                self.render_samples(hittable, camera, lights, &samples);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds one more sample to every pixel on top of the already accumulated ones.
+    /// With adaptive sampling only pixels that did not converge get the sample.
+    pub fn render_pass<H, L>(
+        &mut self,
+        hittable: &H,
+        camera: &Camera,
+        lights: Option<&L>,
+    ) -> Result<(), String>
+    where
+        H: Hittable + Sync,
+        L: Hittable + Sync,
+    {
+        let samples = self
+            .active_pixels()
+            .iter()
+            .map(|active| *active as u32)
+            .collect::<Vec<_>>();
+        self.render_samples(hittable, camera, lights, &samples);
+        Ok(())
+    }
 
-        std::thread::scope(|scope| {
-            let thread_num = std::thread::available_parallelism().unwrap().get() as u32;
-            let tile_height = self.screen_height / thread_num;
+    /// Renders `min_samples` in every pixel, then keeps doubling the samples of the pixels
+    /// that did not converge until the budget of `samples_per_pixel` per pixel is spent.
+    fn render_adaptive<H, L>(
+        &mut self,
+        hittable: &H,
+        camera: &Camera,
+        lights: Option<&L>,
+        adaptive_sampling: &AdaptiveSampling,
+    ) where
+        H: Hittable + Sync,
+        L: Hittable + Sync,
+    {
+        let pixels = self.sample_counts.len();
+        let mut budget = self.samples_per_pixel as usize * pixels;
 
-            let screen_width = self.screen_width;
-            let screen_height = self.screen_height;
-            let samples_per_pixel = self.samples_per_pixel;
-            let max_depth = self.max_depth;
-            let background = self.background;
+        let min_samples = adaptive_sampling.min_samples.min(self.samples_per_pixel);
+        self.render_samples(hittable, camera, lights, &vec![min_samples; pixels]);
+        budget -= min_samples as usize * pixels;
 
-            for (i, buff) in self
-                .buffer
-                .chunks_mut((tile_height * screen_width * 3) as usize)
+        loop {
+            let active = self
+                .active_pixels()
+                .iter()
                 .enumerate()
-            {
-                let bottom = ((thread_num - 1) - i as u32) * tile_height;
-                let top = bottom + tile_height;
-                scope.spawn(move || {
+                .filter_map(|(pixel, active)| active.then_some(pixel))
+                .collect::<Vec<_>>();
+            if active.is_empty() || budget < active.len() {
+                break;
+            }
+            let max_samples = (budget / active.len()) as u32;
+            let mut samples = vec![0; pixels];
+            for pixel in active {
+                samples[pixel] = self.sample_counts[pixel].clamp(1, max_samples);
+                budget -= samples[pixel] as usize;
+            }
+            self.render_samples(hittable, camera, lights, &samples);
+        }
+    }
+
+    /// Pixels that should get more samples, all of them without adaptive sampling.
+    /// Pixels next to unconverged ones stay active too, this catches pixels whose few
+    /// samples happened to be equal (e.g. all missed the light) and look converged.
+    fn active_pixels(&self) -> Vec<bool> {
+        let adaptive_sampling = match self.adaptive_sampling {
+            Some(adaptive_sampling) => adaptive_sampling,
+            None => return vec![true; self.sample_counts.len()],
+        };
+        let needs_samples = (0..self.sample_counts.len())
+            .map(|pixel| {
+                adaptive_sampling.needs_samples(
+                    luminance(&self.accumulation[pixel]),
+                    self.squares[pixel],
+                    self.sample_counts[pixel],
+                )
+            })
+            .collect::<Vec<_>>();
+        let (width, height) = (self.screen_width as usize, self.screen_height as usize);
+        (0..width * height)
+            .map(|pixel| {
+                let (x, y) = (pixel % width, pixel / width);
+                (y.saturating_sub(1)..(y + 2).min(height)).any(|y| {
+                    (x.saturating_sub(1)..(x + 2).min(width)).any(|x| needs_samples[y * width + x])
+                })
+            })
+            .collect()
+    }
+
+    /// Whether adaptive sampling is enabled and all pixels converged.
+    pub fn converged(&self) -> bool {
+        self.adaptive_sampling.is_some() && !self.active_pixels().contains(&true)
+    }
+
+    /// Renders pass after pass while showing the intermediate result in a window.
+    /// Rendering stops after `samples_per_pixel` passes or when the window is closed,
+    /// the accumulated image stays available afterwards (e.g. for [`Renderer::save`]).
+    #[cfg(feature = "sdl")]
+    pub fn render_progressive<H, L>(
+        &mut self,
+        hittable: &H,
+        camera: &Camera,
+        lights: Option<&L>,
+    ) -> Result<(), String>
+    where
+        H: Hittable + Sync,
+        L: Hittable + Sync,
+    {
+        let now = std::time::Instant::now();
+
+        self.clear();
+        self.show(|renderer| {
+            if renderer.samples_taken < renderer.samples_per_pixel && !renderer.converged() {
+                renderer.render_pass(hittable, camera, lights)?;
+                Ok(Some(format!(
+                    "rust_raytracing: {}/{} spp, {:.1}s",
+                    renderer.samples_taken,
+                    renderer.samples_per_pixel,
+                    now.elapsed().as_secs_f32()
+                )))
+            } else {
+                Ok(None)
+            }
+        })
+    }
+
+    /// Discards all accumulated samples.
+    pub fn clear(&mut self) {
+        self.accumulation.fill(Color::default());
+        self.squares.fill(0.0);
+        self.sample_counts.fill(0);
+        self.samples_taken = 0;
+        self.stats = RenderStats::default();
+        self.aovs.clear();
+        if self.aovs_enabled || self.denoiser.is_some() {
+            self.aovs.resize(
+                (self.screen_width * self.screen_height) as usize,
+                AovSample::default(),
+            );
+        }
+    }
+
+    /// Number of samples per pixel accumulated so far, the maximum over all pixels
+    /// with adaptive sampling.
+    pub fn samples_taken(&self) -> u32 {
+        self.samples_taken
+    }
+
+    pub fn stats(&self) -> &RenderStats {
+        &self.stats
+    }
+
+    /// Number of samples accumulated in every pixel, rows go from top to bottom.
+    pub fn sample_counts(&self) -> &[u32] {
+        &self.sample_counts
+    }
+
+    /// Saves the number of samples spent in every pixel to `path`. `exr` and `hdr` files
+    /// store the counts, other formats store a heat map (see [`heat_map`]).
+    pub fn save_heat_map<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let counts = || {
+            self.sample_counts
+                .iter()
+                .map(|count| Color::new(*count as f32, *count as f32, *count as f32))
+                .collect::<Vec<_>>()
+        };
+        match extension(path).as_deref() {
+            Some("exr") => save_exr(path, self.screen_width, self.screen_height, &counts()),
+            Some("hdr") => save_hdr(path, self.screen_width, self.screen_height, &counts()),
+            _ => image::save_buffer(
+                path,
+                &heat_map(&self.sample_counts)
+                    .iter()
+                    .flat_map(|color| {
+                        [color.x, color.y, color.z].map(|value| (255.0 * value + 0.5) as u8)
+                    })
+                    .collect::<Vec<_>>(),
+                self.screen_width,
+                self.screen_height,
+                image::ColorType::Rgb8,
+            )
+            .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Renders `samples[pixel]` new samples in every pixel.
+    fn render_samples<H, L>(
+        &mut self,
+        hittable: &H,
+        camera: &Camera,
+        lights: Option<&L>,
+        samples: &[u32],
+    ) where
+        H: Hittable + Sync,
+        L: Hittable + Sync,
+    {
+        let now = std::time::Instant::now();
+        let screen_width = self.screen_width;
+        let screen_height = self.screen_height;
+        let max_depth = self.max_depth;
+        let background = self.background;
+        let sampler_type = self.sampler_type;
+        let seed = self.seed;
+        let samples_per_pixel = self.samples_per_pixel;
+        let sample_counts = &self.sample_counts;
+        let aovs = !self.aovs.is_empty();
+
+        let mut tiles = Vec::new();
+        for row0 in (0..screen_height).step_by(TILE_SIZE as usize) {
+            for x0 in (0..screen_width).step_by(TILE_SIZE as usize) {
+                tiles.push(Tile {
+                    x0,
+                    row0,
+                    x1: (x0 + TILE_SIZE).min(screen_width),
+                    row1: (row0 + TILE_SIZE).min(screen_height),
+                });
+            }
+        }
+
+        // tiles are handed out to the threads of the pool on demand, so threads that
+        // finish cheap tiles early keep taking the remaining ones
+        let rendered = self.thread_pool.install(|| {
+            tiles
+                .par_iter()
+                .map(|tile| {
                     Self::render_tile(
-                        buff,
-                        (0, top),
-                        (screen_width, bottom),
+                        tile,
                         (screen_width, screen_height),
                         hittable,
                         camera,
-                        samples_per_pixel,
+                        sample_counts,
+                        samples,
+                        sampler_type.create(seed, samples_per_pixel).as_mut(),
                         max_depth,
                         &background,
                         lights,
-                    );
-                });
-            }
+                        aovs,
+                    )
+                })
+                .collect::<Vec<_>>()
         });
 
-        let delta = std::time::Instant::now() - now;
-        println!("Rendered in {}ms", delta.as_millis());
-        Ok(())
+        for (tile, tile_samples) in tiles.iter().zip(rendered.iter()) {
+            self.stats.rays += tile_samples.rays;
+            let tile_width = (tile.x1 - tile.x0) as usize;
+            for (i, row) in (tile.row0..tile.row1).enumerate() {
+                let start = (row * screen_width + tile.x0) as usize;
+                for x in 0..tile_width {
+                    let (pixel, tile_pixel) = (start + x, i * tile_width + x);
+                    self.accumulation[pixel] += tile_samples.colors[tile_pixel];
+                    self.squares[pixel] += tile_samples.squares[tile_pixel];
+                    self.sample_counts[pixel] += samples[pixel];
+                    if aovs {
+                        self.aovs[pixel].add(&tile_samples.aovs[tile_pixel]);
+                    }
+                }
+            }
+        }
+        self.samples_taken = self.sample_counts.iter().copied().max().unwrap_or(0);
+        self.stats.samples += samples.iter().map(|samples| *samples as u64).sum::<u64>();
+        self.resolve();
+        self.stats.render_time += now.elapsed();
+    }
+
+    /// Averages the accumulated samples into `hdr_buffer`, denoises it and updates the display.
+    fn resolve(&mut self) {
+        for ((hdr, sum), count) in self
+            .hdr_buffer
+            .iter_mut()
+            .zip(self.accumulation.iter())
+            .zip(self.sample_counts.iter())
+        {
+            *hdr = if *count == 0 {
+                Color::default()
+            } else {
+                *sum / *count as f32
+            };
+        }
+        if let (Some(denoiser), false) = (self.denoiser, self.aovs.is_empty()) {
+            let albedo = self.aov(Aov::Albedo).unwrap_or_default();
+            let normal = self.aov(Aov::Normal).unwrap_or_default();
+            let depth = self
+                .aovs
+                .iter()
+                .map(|sample| sample.resolve(Aov::Depth).x)
+                .collect::<Vec<_>>();
+            let guides = DenoiseGuides {
+                albedo: &albedo,
+                normal: &normal,
+                depth: &depth,
+            };
+            let (width, height, color) = (self.screen_width, self.screen_height, &self.hdr_buffer);
+            self.hdr_buffer = self
+                .thread_pool
+                .install(|| denoiser.denoise(width, height, color, &guides));
+        }
+        self.update_display();
+    }
+
+    fn update_display(&mut self) {
+        for (pixel, color) in self.buffer.chunks_mut(3).zip(self.hdr_buffer.iter()) {
+            pixel.copy_from_slice(&self.display_transform.apply(color));
+        }
+    }
+
+    /// Linear radiance of every pixel before any display encoding, rows go from top to bottom.
+    /// Already denoised if a denoiser is set.
+    pub fn hdr_buffer(&self) -> &[Color] {
+        &self.hdr_buffer
+    }
+
+    /// Averaged values of the `aov` for every pixel, rows go from top to bottom.
+    /// Returns `None` if AOVs were not recorded.
+    pub fn aov(&self, aov: Aov) -> Option<Vec<Color>> {
+        if self.aovs.is_empty() {
+            return None;
+        }
+        Some(self.aovs.iter().map(|sample| sample.resolve(aov)).collect())
+    }
+
+    /// Saves the `aov` to `path`, the image format is deduced from the file extension.
+    /// `exr` and `hdr` files store the raw values, other formats store a visualization
+    /// (see [`visualize_aov`]).
+    pub fn save_aov<P: AsRef<Path>>(&self, aov: Aov, path: P) -> Result<(), String> {
+        let values = self
+            .aov(aov)
+            .ok_or("AOVs are not enabled, see `Renderer::set_aovs`")?;
+        let path = path.as_ref();
+        match extension(path).as_deref() {
+            Some("exr") => save_exr(path, self.screen_width, self.screen_height, &values),
+            Some("hdr") => save_hdr(path, self.screen_width, self.screen_height, &values),
+            _ => image::save_buffer(
+                path,
+                &visualize_aov(aov, &values),
+                self.screen_width,
+                self.screen_height,
+                image::ColorType::Rgb8,
+            )
+            .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Saves the rendered image to `path`, the image format is deduced from the file extension.
+    /// `exr` and `hdr` files store the linear radiance, other formats (`png`, `ppm`, `jpg`, ...)
+    /// store the 8-bit display image.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        match extension(path).as_deref() {
+            Some("exr") => return self.save_exr(path),
+            Some("hdr") => return self.save_hdr(path),
+            _ => {}
+        }
+        // rows in the buffer are already stored top to bottom (see `render_tile`)
+        image::save_buffer(
+            path,
+            &self.buffer,
+            self.screen_width,
+            self.screen_height,
+            image::ColorType::Rgb8,
+        )
+        .map_err(|e| e.to_string())
     }
 
+    /// Encodes the rendered image with the given `format` and writes it into `writer`.
+    pub fn write_to<W: Write>(
+        &self,
+        mut writer: W,
+        format: ImageOutputFormat,
+    ) -> Result<(), String> {
+        let image =
+            image::RgbImage::from_raw(self.screen_width, self.screen_height, self.buffer.clone())
+                .ok_or("buffer size does not match the screen size")?;
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut writer, format)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Saves the linear radiance as an OpenEXR file.
+    pub fn save_exr<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        save_exr(
+            path,
+            self.screen_width,
+            self.screen_height,
+            &self.hdr_buffer,
+        )
+    }
+
+    /// Saves the linear radiance as a Radiance `.hdr` file.
+    pub fn save_hdr<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        save_hdr(
+            path,
+            self.screen_width,
+            self.screen_height,
+            &self.hdr_buffer,
+        )
+    }
+
+    #[cfg(feature = "sdl")]
     pub fn present(&mut self) -> Result<(), String> {
+        self.show(|_| Ok(None))
+    }
+
+    /// Opens a window with the display image and keeps it open until it is closed.
+    /// `update` is called every frame, if it returns a new window title the image is redrawn.
+    #[cfg(feature = "sdl")]
+    fn show<F>(&mut self, mut update: F) -> Result<(), String>
+    where
+        F: FnMut(&mut Self) -> Result<Option<String>, String>,
+    {
         let context = sdl2::init()?;
         let video_subsystem = context.video()?;
 
@@ -113,14 +665,7 @@ impl<'a> Renderer {
             )
             .map_err(|e| e.to_string())?;
 
-        texture
-            .update(None, &self.buffer, self.pitch as usize)
-            .unwrap();
-
-        canvas.clear();
-        canvas.copy(&texture, None, None)?;
-        canvas.present();
-
+        let mut redraw = true;
         let mut event_pump = context.event_pump()?;
         'running: loop {
             for event in event_pump.poll_iter() {
@@ -133,26 +678,65 @@ impl<'a> Renderer {
                     _ => {}
                 }
             }
+
+            if redraw {
+                texture
+                    .update(None, &self.buffer, (self.screen_width * 3) as usize)
+                    .map_err(|e| e.to_string())?;
+                canvas.clear();
+                canvas.copy(&texture, None, None)?;
+                canvas.present();
+            }
+
+            match update(self)? {
+                Some(title) => {
+                    canvas
+                        .window_mut()
+                        .set_title(&title)
+                        .map_err(|e| e.to_string())?;
+                    redraw = true;
+                }
+                None => {
+                    redraw = false;
+                    std::thread::sleep(std::time::Duration::from_millis(16));
+                }
+            }
         }
         Ok(())
     }
 
+    /// Radiance along `r`. For camera rays `first_hit` records the AOVs of the first hit.
+    #[allow(clippy::too_many_arguments)]
     fn ray_color(
         r: &Ray,
         hittable: &impl Hittable,
         max_depth: u32,
         background: &Color,
         lights: Option<&impl Hittable>,
+        sampler: &mut dyn Sampler,
+        rays: &mut u64,
+        first_hit: Option<(&Camera, &mut AovSample)>,
     ) -> Color {
+        if max_depth == 0 && first_hit.is_none() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        *rays += 1;
+        let hit = hittable.hit(r, 0.001, f32::INFINITY);
+        if let Some((camera, aov)) = first_hit {
+            aov.add(&match &hit {
+                Some(hit) => AovSample::from_hit(hit, camera),
+                None => AovSample::from_miss(background),
+            });
+        }
         if max_depth == 0 {
             return Color::new(0.0, 0.0, 0.0);
         }
-        if let Some(hit) = hittable.hit(r, 0.001, f32::INFINITY) {
+        if let Some(hit) = hit {
             let emitted = hit
                 .material
                 .unwrap()
                 .emit(r, &hit, hit.u, hit.v, &hit.point);
-            if let Some(scatter_rec) = hit.material.unwrap().scatter(r, &hit) {
+            if let Some(scatter_rec) = hit.material.unwrap().scatter(r, &hit, sampler) {
                 if scatter_rec.is_specular {
                     return scatter_rec.attenuation
                         * Self::ray_color(
@@ -161,6 +745,9 @@ impl<'a> Renderer {
                             max_depth - 1,
                             background,
                             lights,
+                            sampler,
+                            rays,
+                            None,
                         );
                 }
                 let (ray, pdf) = if let Some(lights) = lights {
@@ -169,17 +756,26 @@ impl<'a> Renderer {
                         &light_pdf,
                         scatter_rec.pdf.borrow().as_ref().unwrap().borrow(),
                     );
-                    let ray = Ray::new(hit.point, mixture.generate(), r.time);
+                    let ray = Ray::new(hit.point, mixture.generate(sampler), r.time);
                     (ray, mixture.value(&ray.direction))
                 } else {
                     let mixture = scatter_rec.pdf.unwrap();
-                    let ray = Ray::new(hit.point, mixture.generate(), r.time);
+                    let ray = Ray::new(hit.point, mixture.generate(sampler), r.time);
                     (ray, mixture.value(&ray.direction))
                 };
                 emitted
                     + scatter_rec.attenuation
                         * hit.material.unwrap().scattering_pdf(r, &hit, &ray)
-                        * Self::ray_color(&ray, hittable, max_depth - 1, background, lights)
+                        * Self::ray_color(
+                            &ray,
+                            hittable,
+                            max_depth - 1,
+                            background,
+                            lights,
+                            sampler,
+                            rays,
+                            None,
+                        )
                         / pdf
             } else {
                 emitted
@@ -189,72 +785,277 @@ impl<'a> Renderer {
         }
     }
 
+    /// Renders `samples[pixel]` new samples in every pixel of the `tile` continuing after
+    /// the `sample_counts[pixel]` samples already taken.
     #[allow(clippy::too_many_arguments)]
     fn render_tile(
-        buffer: &mut [u8],
-        top_left: (u32, u32),
-        bot_right: (u32, u32),
+        tile: &Tile,
         window_size: (u32, u32),
         hittable: &'a impl Hittable,
         camera: &Camera,
-        samples_per_pixel: u32,
+        sample_counts: &[u32],
+        samples: &[u32],
+        sampler: &mut dyn Sampler,
         max_depth: u32,
         background: &Color,
         lights: Option<&impl Hittable>,
-    ) {
-        println!(
-            "rendering buffer x_range: {:?}, y_range: {:?}",
-            (top_left.0..bot_right.0),
-            (bot_right.1..top_left.1)
-        );
-        let mut rng = rand::thread_rng();
-        let uniform = rand::distributions::Uniform::new(0.0, 1.0);
-        (bot_right.1..top_left.1).for_each(move |y| {
-            (top_left.0..bot_right.0).for_each(|x| {
+        aovs: bool,
+    ) -> TileSamples {
+        let pixels = ((tile.x1 - tile.x0) * (tile.row1 - tile.row0)) as usize;
+        let mut tile_samples = TileSamples {
+            colors: Vec::with_capacity(pixels),
+            squares: Vec::with_capacity(pixels),
+            aovs: Vec::with_capacity(if aovs { pixels } else { 0 }),
+            rays: 0,
+        };
+        for row in tile.row0..tile.row1 {
+            // for OpenGl reverse y coord
+            let y = window_size.1 - 1 - row;
+            for x in tile.x0..tile.x1 {
                 let mut color = Color::new(0.0, 0.0, 0.0);
-                for _ in 0..samples_per_pixel {
-                    let u = (x as f32 + uniform.sample(&mut rng)) / (window_size.0 - 1) as f32;
-                    let v = (y as f32 + uniform.sample(&mut rng)) / (window_size.1 - 1) as f32;
-                    let r = camera.get_ray(u, v);
-                    color += Self::ray_color(&r, hittable, max_depth, background, lights);
+                let mut squares = 0.0;
+                let mut aov = AovSample::default();
+                let pixel = (row * window_size.0 + x) as usize;
+                let start = sample_counts[pixel];
+                for sample_index in start..start + samples[pixel] {
+                    sampler.start_pixel_sample((x, y), sample_index);
+                    let (dx, dy) = sampler.get_2d();
+                    let u = (x as f32 + dx) / (window_size.0 - 1) as f32;
+                    let v = (y as f32 + dy) / (window_size.1 - 1) as f32;
+                    let r = camera.get_ray(u, v, sampler);
+                    let sample_color = Self::ray_color(
+                        &r,
+                        hittable,
+                        max_depth,
+                        background,
+                        lights,
+                        sampler,
+                        &mut tile_samples.rays,
+                        if aovs { Some((camera, &mut aov)) } else { None },
+                    );
+                    color += sample_color;
+                    squares += luminance(&sample_color).powi(2);
                 }
-                Self::write_pixel(buffer, top_left, bot_right, x, y, &color, samples_per_pixel);
-            });
-        });
-        println!(
-            "rendering buffer x_range: {:?}, y_range: {:?} finished",
-            (top_left.0..bot_right.0),
-            (bot_right.1..top_left.1)
+                tile_samples.colors.push(color);
+                tile_samples.squares.push(squares);
+                if aovs {
+                    tile_samples.aovs.push(aov);
+                }
+            }
+        }
+        tile_samples
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+}
+
+/// Writes linear `colors` stored row by row from top to bottom as an OpenEXR file.
+fn save_exr<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    colors: &[Color],
+) -> Result<(), String> {
+    let width = width as usize;
+    exr::prelude::write_rgb_file(path, width, height as usize, |x, y| {
+        let color = colors[y * width + x];
+        (color.x, color.y, color.z)
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// Writes linear `colors` stored row by row from top to bottom as a Radiance `.hdr` file.
+fn save_hdr<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    colors: &[Color],
+) -> Result<(), String> {
+    let file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+    let pixels = colors
+        .iter()
+        .map(|color| image::Rgb([color.x, color.y, color.z]))
+        .collect::<Vec<_>>();
+    image::codecs::hdr::HdrEncoder::new(std::io::BufWriter::new(file))
+        .encode(&pixels, width as usize, height as usize)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Dielectric, Lambertian, Metal, Point3, SolidTexture, Sphere, Vec3, World};
+
+    fn scene() -> (World, Camera) {
+        let mut world = World::default();
+        world.add(Sphere::new(
+            Point3::new(0.0, 0.0, -1.0),
+            0.5,
+            Lambertian::new(SolidTexture::from_rgb(0.7, 0.3, 0.3)),
+        ));
+        world.add(Sphere::new(
+            Point3::new(1.0, 0.0, -1.0),
+            0.5,
+            Dielectric::new(1.5),
+        ));
+        world.add(Sphere::new(
+            Point3::new(0.0, -100.5, -1.0),
+            100.0,
+            Metal::new(SolidTexture::from_rgb(0.8, 0.8, 0.8), 0.3),
+        ));
+        let camera = Camera::new(
+            &Point3::new(0.0, 0.5, 2.0),
+            &Point3::new(0.0, 0.0, -1.0),
+            &Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.0,
+            0.1,
+            3.0,
+            0.0,
+            1.0,
         );
+        (world, camera)
     }
 
-    #[inline]
-    fn write_pixel(
-        buffer: &mut [u8],
-        top_left: (u32, u32),
-        bot_right: (u32, u32),
-        x: u32,
-        y: u32,
-        color: &Color,
-        samples_per_pixel: u32,
-    ) {
-        let r = (255.999
-            * (color.x / samples_per_pixel as f32)
-                .sqrt()
-                .clamp(0.0, 0.999)) as u8;
-        let g = (255.999
-            * (color.y / samples_per_pixel as f32)
-                .sqrt()
-                .clamp(0.0, 0.999)) as u8;
-        let b = (255.999
-            * (color.z / samples_per_pixel as f32)
-                .sqrt()
-                .clamp(0.0, 0.999)) as u8;
-
-        let pitch = (bot_right.0 - top_left.0) * 3;
-        let offset = (top_left.1 - 1 - y) as usize * pitch as usize + x as usize * 3; // for OpenGl reverse y coord
-        buffer[offset] = r;
-        buffer[offset + 1] = g;
-        buffer[offset + 2] = b;
+    fn render(seed: u64, threads: usize) -> Vec<Color> {
+        let (world, camera) = scene();
+        let mut renderer = Renderer::new(20, 20, 4, 5, Color::new(0.5, 0.7, 1.0)).unwrap();
+        renderer.set_seed(seed);
+        renderer.set_threads(threads).unwrap();
+        renderer
+            .render::<World, World>(&world, &camera, None)
+            .unwrap();
+        renderer.hdr_buffer().to_vec()
+    }
+
+    /// Renders the test scene into a non-square image.
+    fn rendered() -> Renderer {
+        let (world, camera) = scene();
+        let mut renderer = Renderer::new(8, 6, 2, 5, Color::new(0.5, 0.7, 1.0)).unwrap();
+        renderer
+            .render::<World, World>(&world, &camera, None)
+            .unwrap();
+        renderer
+    }
+
+    fn bits(buffer: &[Color]) -> Vec<[u32; 3]> {
+        buffer
+            .iter()
+            .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+            .collect()
+    }
+
+    #[test]
+    fn renderer_same_seed_same_image() {
+        assert_eq!(bits(&render(7, 1)), bits(&render(7, 4)));
+        assert_ne!(bits(&render(7, 1)), bits(&render(8, 1)));
+    }
+
+    #[test]
+    fn renderer_progressive_passes() {
+        let (world, camera) = scene();
+        let mut renderer = Renderer::new(20, 20, 1, 5, Color::new(0.5, 0.7, 1.0)).unwrap();
+        renderer.set_seed(7);
+        for pass in 1..=4 {
+            renderer
+                .render_pass::<World, World>(&world, &camera, None)
+                .unwrap();
+            assert_eq!(renderer.samples_taken(), pass);
+        }
+        assert_eq!(bits(renderer.hdr_buffer()), bits(&render(7, 1)));
+    }
+
+    #[test]
+    fn renderer_write_image() {
+        let renderer = rendered();
+        let mut png = Vec::new();
+        renderer.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (8, 6));
+        assert_eq!(image.into_raw(), renderer.buffer);
+
+        let dir = std::env::temp_dir().join("rust_raytracing_renderer_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        renderer.save(dir.join("image.png")).unwrap();
+        let image = image::open(dir.join("image.png")).unwrap().to_rgb8();
+        assert_eq!(image.into_raw(), renderer.buffer);
+    }
+
+    #[test]
+    fn renderer_save_linear() {
+        let renderer = rendered();
+        let dir = std::env::temp_dir().join("rust_raytracing_renderer_test");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        renderer.save(dir.join("image.exr")).unwrap();
+        let exr = exr::prelude::read_first_rgba_layer_from_file(
+            dir.join("image.exr"),
+            |size, _| (size.width(), vec![Color::default(); size.area()]),
+            |(width, pixels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                pixels[position.y() * *width + position.x()] = Color::new(r, g, b);
+            },
+        )
+        .unwrap();
+        let (width, pixels) = &exr.layer_data.channel_data.pixels;
+        assert_eq!((*width, pixels.len()), (8, 8 * 6));
+        assert_eq!(bits(pixels), bits(renderer.hdr_buffer()));
+
+        renderer.save(dir.join("image.hdr")).unwrap();
+        let file = std::fs::File::open(dir.join("image.hdr")).unwrap();
+        let decoder = image::codecs::hdr::HdrDecoder::new(std::io::BufReader::new(file)).unwrap();
+        let metadata = decoder.metadata();
+        assert_eq!((metadata.width, metadata.height), (8, 6));
+        // RGBE shares one exponent between the channels, which keeps about 8 bits of precision
+        let pixels = decoder.read_image_hdr().unwrap();
+        for (pixel, color) in pixels.iter().zip(renderer.hdr_buffer()) {
+            let max = color.x.max(color.y).max(color.z);
+            for (read, written) in pixel.0.iter().zip(&[color.x, color.y, color.z]) {
+                assert!((read - written).abs() <= max / 128.0);
+            }
+        }
+    }
+
+    #[test]
+    fn renderer_adaptive_sampling() {
+        let (world, camera) = scene();
+        let mut renderer = Renderer::new(20, 20, 16, 5, Color::new(0.5, 0.7, 1.0)).unwrap();
+        renderer.set_adaptive_sampling(Some(AdaptiveSampling::new(0.05, 4)));
+        renderer
+            .render::<World, World>(&world, &camera, None)
+            .unwrap();
+        let counts = renderer.sample_counts();
+        assert!(counts.iter().sum::<u32>() <= 16 * 20 * 20);
+        // the sky converges right after the minimal samples, the rest goes to the spheres
+        assert_eq!(counts[0], 4);
+        assert!(renderer.samples_taken() > 16);
+    }
+
+    #[test]
+    fn renderer_aovs() {
+        let (world, camera) = scene();
+        let mut renderer = Renderer::new(20, 20, 2, 5, Color::new(0.5, 0.7, 1.0)).unwrap();
+        renderer
+            .render::<World, World>(&world, &camera, None)
+            .unwrap();
+        assert!(renderer.aov(Aov::Depth).is_none());
+        let (image, rays) = (bits(renderer.hdr_buffer()), renderer.stats().rays);
+
+        // AOVs come from the camera rays, they do not trace any more rays
+        renderer.set_aovs(true);
+        renderer
+            .render::<World, World>(&world, &camera, None)
+            .unwrap();
+        assert_eq!(bits(renderer.hdr_buffer()), image);
+        assert_eq!(renderer.stats().rays, rays);
+        let center = 10 * 20 + 10;
+        let depth = renderer.aov(Aov::Depth).unwrap()[center].x;
+        assert!(depth > 2.0 && depth < 3.0);
+        assert_eq!(renderer.aov(Aov::ObjectId).unwrap()[center].x, 1.0);
+        let albedo = renderer.aov(Aov::Albedo).unwrap()[center];
+        assert!((albedo.x - 0.7).abs() < 1e-5 && (albedo.y - 0.3).abs() < 1e-5);
     }
 }
@@ -0,0 +1,468 @@
+use crate::ray::Ray;
+
+/// Source of the sample values used by the integrator.
+/// Every sample of a pixel is started with [`Sampler::start_pixel_sample`], after that all
+/// values are a deterministic function of the render seed, the pixel and the sample index.
+/// Each call to `get_1d` or `get_2d` consumes the next dimension of the sample, so the pixel
+/// jitter, the lens, the time and every bounce get their own well distributed dimensions.
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), sample_index: u32);
+    /// Uniform value in [0, 1).
+    fn get_1d(&mut self) -> f32;
+    /// Uniform point in [0, 1)^2.
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SamplerType {
+    #[default]
+    Random,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerType {
+    pub fn create(&self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            Self::Random => Box::new(RandomSampler::new(seed)),
+            Self::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            Self::Halton => Box::new(HaltonSampler::new(seed)),
+            Self::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+/// Independent uniform random values.
+#[derive(Debug, Clone)]
+pub struct RandomSampler {
+    seed: u64,
+    rng: Pcg32,
+}
+
+impl RandomSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Pcg32::new(seed),
+        }
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), sample_index: u32) {
+        self.rng = Pcg32::new(hash(&[
+            self.seed,
+            pixel.0 as u64,
+            pixel.1 as u64,
+            sample_index as u64,
+        ]));
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.next_f32()
+    }
+}
+
+/// Jittered samples, every dimension is split into `samples_per_pixel` strata
+/// (a grid of them for 2D) which are visited in a random order.
+#[derive(Debug, Clone)]
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    pixel: (u32, u32),
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> Self {
+        Self {
+            seed,
+            samples_per_pixel: samples_per_pixel.max(1),
+            pixel: (0, 0),
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_hash(&mut self) -> u64 {
+        self.dimension += 1;
+        hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ])
+    }
+
+    fn stratum(&self, strata: u32, dimension_hash: u64) -> u32 {
+        // every `strata` samples use a new permutation
+        let round = self.sample_index / strata;
+        let permutation = hash(&[dimension_hash, round as u64]) as u32;
+        permute(self.sample_index % strata, strata, permutation)
+    }
+
+    fn jitter(&self, dimension_hash: u64) -> Pcg32 {
+        Pcg32::new(hash(&[dimension_hash, self.sample_index as u64, 1]))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), sample_index: u32) {
+        self.pixel = pixel;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension_hash = self.next_hash();
+        let strata = self.samples_per_pixel;
+        let stratum = self.stratum(strata, dimension_hash);
+        let jitter = self.jitter(dimension_hash).next_f32();
+        ((stratum as f32 + jitter) / strata as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension_hash = self.next_hash();
+        let nx = (self.samples_per_pixel as f32).sqrt().ceil() as u32;
+        let ny = self.samples_per_pixel.div_ceil(nx);
+        let stratum = self.stratum(nx * ny, dimension_hash);
+        let mut jitter = self.jitter(dimension_hash);
+        let x = ((stratum % nx) as f32 + jitter.next_f32()) / nx as f32;
+        let y = ((stratum / nx) as f32 + jitter.next_f32()) / ny as f32;
+        (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+    }
+}
+
+/// Halton sequence with a prime base per dimension, decorrelated between pixels
+/// by Owen scrambling the digits of every dimension with a per pixel seed.
+#[derive(Debug, Clone)]
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: (u32, u32),
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: (0, 0),
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn sample_dimension(&mut self) -> f32 {
+        let dimension = self.dimension as usize;
+        self.dimension += 1;
+        let dimension_hash = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            dimension as u64,
+        ]);
+        match PRIMES.get(dimension) {
+            Some(&base) => owen_scrambled_radical_inverse(base, self.sample_index, dimension_hash),
+            // past the tabulated primes fall back to random values
+            None => Pcg32::new(hash(&[dimension_hash, self.sample_index as u64])).next_f32(),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), sample_index: u32) {
+        self.pixel = pixel;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.sample_dimension()
+    }
+}
+
+/// Owen-scrambled Sobol sequence using hash based nested uniform scrambling
+/// (Burley 2020, "Practical Hash-based Owen Scrambling"). Every 1D/2D request uses the first
+/// Sobol dimensions with its own scrambling and sample order, which keeps all pairs of
+/// dimensions well stratified.
+#[derive(Debug, Clone)]
+pub struct SobolSampler {
+    seed: u64,
+    pixel: (u32, u32),
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: (0, 0),
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_seeds(&mut self) -> (u32, u32, u32) {
+        self.dimension += 1;
+        let h = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ]);
+        let h2 = hash(&[h]);
+        (h as u32, (h >> 32) as u32, h2 as u32)
+    }
+
+    #[inline]
+    fn to_f32(value: u32) -> f32 {
+        ((value >> 8) as f32 * (1.0 / (1u32 << 24) as f32)).min(ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), sample_index: u32) {
+        self.pixel = pixel;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let (shuffle, scramble, _) = self.next_seeds();
+        let index = nested_uniform_scramble(self.sample_index, shuffle);
+        Self::to_f32(nested_uniform_scramble(sobol_0(index), scramble))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let (shuffle, scramble_x, scramble_y) = self.next_seeds();
+        let index = nested_uniform_scramble(self.sample_index, shuffle);
+        (
+            Self::to_f32(nested_uniform_scramble(sobol_0(index), scramble_x)),
+            Self::to_f32(nested_uniform_scramble(sobol_1(index), scramble_y)),
+        )
+    }
+}
+
+/// Largest f32 below 1.0.
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Radical inverse of `index` with every digit permuted by a permutation that depends on
+/// all the digits before it.
+fn owen_scrambled_radical_inverse(base: u32, mut index: u32, seed: u64) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_n = 1.0;
+    let mut reversed = 0u64;
+    // keep adding digits (zeros of the index get scrambled too) until f32 precision is reached
+    while 1.0 - (base - 1) as f32 * inv_base_n < 1.0 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit_hash = hash(&[seed, reversed]) as u32;
+        let digit = permute(digit, base, digit_hash);
+        reversed = reversed * base as u64 + digit as u64;
+        inv_base_n *= inv_base;
+        index = next;
+    }
+    (reversed as f32 * inv_base_n).min(ONE_MINUS_EPSILON)
+}
+
+/// First Sobol dimension (van der Corput sequence) as a 0.32 fixed point value.
+#[inline]
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+/// Second Sobol dimension as a 0.32 fixed point value.
+#[inline]
+fn sobol_1(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+#[inline]
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+#[inline]
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Element `index` of a random permutation of `0..len` selected by `seed`
+/// (Kensler 2013, "Correlated Multi-Jittered Sampling").
+fn permute(mut index: u32, len: u32, seed: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= seed >> 16;
+        index ^= (index & w) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= seed >> 23;
+        index ^= (index & w) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & w) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= w;
+        index ^= index >> 5;
+        if index < len {
+            break;
+        }
+    }
+    (index.wrapping_add(seed)) % len
+}
+
+/// PCG-XSH-RR generator, small and identical on every platform.
+#[derive(Debug, Clone)]
+pub struct Pcg32 {
+    state: u64,
+}
+
+impl Pcg32 {
+    const MULTIPLIER: u64 = 6364136223846793005;
+    const INCREMENT: u64 = 1442695040888963407;
+
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(Self::MULTIPLIER)
+            .wrapping_add(Self::INCREMENT);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Uniform value in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+}
+
+/// Mixes all `values` into a single well distributed 64 bit value.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, value| {
+        mix(h ^ mix(value.wrapping_add(0x9e3779b97f4a7c15)))
+    })
+}
+
+/// Uniform value in [0, 1) derived from the ray, for random decisions that have to stay
+/// a pure function of the ray (e.g. inside [`crate::Hittable::hit`]).
+pub fn ray_hash_1d(ray: &Ray) -> f32 {
+    let h = hash(&[
+        ray.origin.x.to_bits() as u64 | (ray.origin.y.to_bits() as u64) << 32,
+        ray.origin.z.to_bits() as u64 | (ray.direction.x.to_bits() as u64) << 32,
+        ray.direction.y.to_bits() as u64 | (ray.direction.z.to_bits() as u64) << 32,
+        ray.time.to_bits() as u64,
+    ]);
+    (h >> 40) as f32 * (1.0 / (1u64 << 24) as f32)
+}
+
+// splitmix64 finalizer
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLER_TYPES: [SamplerType; 4] = [
+        SamplerType::Random,
+        SamplerType::Stratified,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+    ];
+
+    #[test]
+    fn sampler_values_in_unit_range() {
+        for sampler_type in SAMPLER_TYPES {
+            let mut sampler = sampler_type.create(3, 16);
+            for sample_index in 0..64 {
+                sampler.start_pixel_sample((5, 7), sample_index);
+                for _ in 0..40 {
+                    let v = sampler.get_1d();
+                    assert!((0.0..1.0).contains(&v), "{:?}: {}", sampler_type, v);
+                    let (x, y) = sampler.get_2d();
+                    assert!((0.0..1.0).contains(&x), "{:?}: {}", sampler_type, x);
+                    assert!((0.0..1.0).contains(&y), "{:?}: {}", sampler_type, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sampler_deterministic() {
+        for sampler_type in SAMPLER_TYPES {
+            let mut a = sampler_type.create(3, 16);
+            let mut b = sampler_type.create(3, 16);
+            // the order pixels are visited in must not matter
+            b.start_pixel_sample((0, 0), 9);
+            b.get_2d();
+            a.start_pixel_sample((1, 2), 3);
+            b.start_pixel_sample((1, 2), 3);
+            for _ in 0..8 {
+                assert_eq!(a.get_1d().to_bits(), b.get_1d().to_bits());
+            }
+        }
+    }
+
+    #[test]
+    fn sampler_low_discrepancy_stratified() {
+        // the first 16 samples put exactly one value into every 1/16 of the first dimension
+        for sampler_type in &SAMPLER_TYPES[1..] {
+            let mut sampler = sampler_type.create(11, 16);
+            let mut strata = [false; 16];
+            for sample_index in 0..16 {
+                sampler.start_pixel_sample((3, 4), sample_index);
+                strata[(sampler.get_1d() * 16.0) as usize] = true;
+            }
+            assert!(strata.iter().all(|s| *s), "{:?}", sampler_type);
+        }
+    }
+}
@@ -0,0 +1,736 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::bvh::BVH;
+use crate::camera::Camera;
+use crate::hittable::Hittable;
+use crate::material::{Dielectric, DiffuseLight, Isotropic, Lambertian, Material, Metal};
+use crate::objects::{Box3d, MovingSphere, Sphere, XYRect, XZRect, YZRect};
+use crate::renderer::Renderer;
+use crate::sampler::{hash, SamplerType};
+use crate::texture::{CheckerTexture, ImageTexture, NoiseTexture, SolidTexture, Texture};
+use crate::transforms::{ConstantMedium, FlipFace, Rotate, Translate};
+use crate::vec3::{Color, Point3, Vec3};
+use crate::world::World;
+
+pub type SceneTexture = Arc<dyn Texture + Send + Sync>;
+pub type SceneMaterial = Arc<dyn Material + Send + Sync>;
+pub type SceneObject = Arc<dyn Hittable + Send + Sync>;
+
+/// Renderer settings stored in the `[renderer]` table of a scene file.
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub background: Color,
+    pub seed: u64,
+    pub sampler: SamplerType,
+    /// Whether the objects are put into a `BVH` instead of a flat `World`.
+    pub bvh: bool,
+}
+
+/// Everything needed to render an image described by a scene file.
+///
+/// ```toml
+/// [renderer]
+/// width = 400
+/// height = 400
+/// samples_per_pixel = 16
+/// background = [0.0, 0.0, 0.0]
+///
+/// [camera]
+/// look_from = [278.0, 278.0, -800.0]
+/// look_at = [278.0, 278.0, 0.0]
+/// vfov = 40.0
+///
+/// [materials.white]
+/// lambertian = { albedo = [0.73, 0.73, 0.73] }
+/// [materials.light]
+/// diffuse_light = { emit = [15.0, 15.0, 15.0] }
+///
+/// [[objects]]
+/// xz_rect = { x = [213.0, 343.0], z = [227.0, 332.0], k = 554.0 }
+/// material = "light"
+/// transforms = ["flip_face"]
+/// light = true
+///
+/// [[objects]]
+/// box = { min = [0.0, 0.0, 0.0], max = [165.0, 330.0, 165.0] }
+/// material = "white"
+/// transforms = [{ rotate_y = 15.0 }, { translate = [265.0, 0.0, 295.0] }]
+/// ```
+///
+/// Textures are referenced by name or given inline as a color, materials are referenced by
+/// name. Objects marked with `light = true` are also added to `lights`.
+pub struct Scene {
+    pub world: Box<dyn Hittable + Send + Sync>,
+    pub lights: Option<World>,
+    pub camera: Camera,
+    pub settings: RenderSettings,
+}
+
+impl Scene {
+    /// Loads a scene file, image texture paths are relative to the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        Self::load_with(path, |_| {})
+    }
+
+    /// Loads a scene file and lets `overrides` change the settings from the file before
+    /// the scene is built (e.g. the resolution, which also changes the camera aspect ratio).
+    pub fn load_with<P, F>(path: P, overrides: F) -> Result<Self, String>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut RenderSettings),
+    {
+        let path = path.as_ref();
+        let source =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse_with(&source, base_dir, overrides)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parses a scene from `source`, image texture paths are relative to `base_dir`.
+    pub fn parse(source: &str, base_dir: &Path) -> Result<Self, String> {
+        Self::parse_with(source, base_dir, |_| {})
+    }
+
+    /// Like [`Scene::parse`], `overrides` can change the settings before the scene is built.
+    pub fn parse_with<F>(source: &str, base_dir: &Path, overrides: F) -> Result<Self, String>
+    where
+        F: FnOnce(&mut RenderSettings),
+    {
+        let desc: SceneDesc = toml::from_str(source).map_err(|e| e.to_string())?;
+        SceneBuilder {
+            source,
+            base_dir,
+            desc: &desc,
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            seed: 0,
+        }
+        .build(overrides)
+    }
+
+    /// Creates a renderer configured with the scene settings.
+    pub fn renderer(&self) -> Result<Renderer, String> {
+        let settings = &self.settings;
+        let mut renderer = Renderer::new(
+            settings.width,
+            settings.height,
+            settings.samples_per_pixel,
+            settings.max_depth,
+            settings.background,
+        )?;
+        renderer.set_seed(settings.seed);
+        renderer.set_sampler(settings.sampler);
+        Ok(renderer)
+    }
+
+    /// Renders the scene with `renderer`.
+    pub fn render(&self, renderer: &mut Renderer) -> Result<(), String> {
+        renderer.render(&self.world, &self.camera, self.lights.as_ref())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    #[serde(default)]
+    renderer: RendererDesc,
+    camera: Spanned<CameraDesc>,
+    #[serde(default)]
+    textures: HashMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectDesc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RendererDesc {
+    width: u32,
+    height: u32,
+    samples_per_pixel: u32,
+    max_depth: u32,
+    background: [f32; 3],
+    seed: u64,
+    sampler: SamplerDesc,
+    bvh: bool,
+}
+
+impl Default for RendererDesc {
+    fn default() -> Self {
+        Self {
+            width: 400,
+            height: 400,
+            samples_per_pixel: 16,
+            max_depth: 10,
+            background: [0.0; 3],
+            seed: 0,
+            sampler: SamplerDesc::Random,
+            bvh: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SamplerDesc {
+    Random,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    look_from: [f32; 3],
+    look_at: [f32; 3],
+    #[serde(default = "CameraDesc::default_up")]
+    up: [f32; 3],
+    vfov: f32,
+    #[serde(default)]
+    aperture: f32,
+    /// Defaults to the distance between `look_from` and `look_at`.
+    focus_distance: Option<f32>,
+    #[serde(default = "CameraDesc::default_time")]
+    time: [f32; 2],
+}
+
+impl CameraDesc {
+    fn default_up() -> [f32; 3] {
+        [0.0, 1.0, 0.0]
+    }
+
+    fn default_time() -> [f32; 2] {
+        [0.0, 1.0]
+    }
+}
+
+/// Either a name of a texture from the `[textures]` table or a solid color.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextureRef {
+    Color([f32; 3]),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+    Solid { color: [f32; 3] },
+    Checker { odd: TextureRef, even: TextureRef },
+    Noise { scale: f32 },
+    Image { path: String },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian {
+        albedo: TextureRef,
+    },
+    Metal {
+        albedo: TextureRef,
+        #[serde(default)]
+        fuzz: f32,
+    },
+    Dielectric {
+        index_of_refraction: f32,
+    },
+    DiffuseLight {
+        emit: TextureRef,
+    },
+    Isotropic {
+        albedo: TextureRef,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    sphere: Option<SphereDesc>,
+    moving_sphere: Option<MovingSphereDesc>,
+    xy_rect: Option<RectDesc>,
+    xz_rect: Option<RectDesc>,
+    yz_rect: Option<RectDesc>,
+    #[serde(rename = "box")]
+    box3d: Option<BoxDesc>,
+    /// Can be left out for the boundary of a medium.
+    material: Option<String>,
+    /// Applied in order, the first one is applied to the object itself.
+    #[serde(default)]
+    transforms: Vec<TransformDesc>,
+    /// Turns the object into the boundary of a volume, applied after the transforms.
+    medium: Option<MediumDesc>,
+    /// Whether the object is also sampled as a light.
+    #[serde(default)]
+    light: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDesc {
+    center: [f32; 3],
+    radius: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MovingSphereDesc {
+    center0: [f32; 3],
+    center1: [f32; 3],
+    time0: f32,
+    time1: f32,
+    radius: f32,
+}
+
+/// Axis aligned rectangle, the two ranges are along the axes in the name of the shape
+/// (`x`, `y` for `xy_rect`) and `k` is the position along the third axis.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RectDesc {
+    x: Option<[f32; 2]>,
+    y: Option<[f32; 2]>,
+    z: Option<[f32; 2]>,
+    k: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BoxDesc {
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDesc {
+    Translate([f32; 3]),
+    /// Angle in degrees.
+    RotateY(f32),
+    FlipFace,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MediumDesc {
+    density: f32,
+    material: String,
+}
+
+struct SceneBuilder<'a> {
+    source: &'a str,
+    base_dir: &'a Path,
+    desc: &'a SceneDesc,
+    textures: HashMap<&'a str, SceneTexture>,
+    materials: HashMap<&'a str, SceneMaterial>,
+    /// Render seed, procedural textures derive their patterns from it.
+    seed: u64,
+}
+
+impl<'a> SceneBuilder<'a> {
+    fn build<F: FnOnce(&mut RenderSettings)>(mut self, overrides: F) -> Result<Scene, String> {
+        let desc = self.desc;
+        let renderer = &desc.renderer;
+        let mut settings = RenderSettings {
+            width: renderer.width,
+            height: renderer.height,
+            samples_per_pixel: renderer.samples_per_pixel,
+            max_depth: renderer.max_depth,
+            background: vec3(renderer.background),
+            seed: renderer.seed,
+            sampler: match renderer.sampler {
+                SamplerDesc::Random => SamplerType::Random,
+                SamplerDesc::Stratified => SamplerType::Stratified,
+                SamplerDesc::Halton => SamplerType::Halton,
+                SamplerDesc::Sobol => SamplerType::Sobol,
+            },
+            bvh: renderer.bvh,
+        };
+        overrides(&mut settings);
+        if settings.width == 0 || settings.height == 0 {
+            return Err("renderer: width and height must not be 0".to_string());
+        }
+        self.seed = settings.seed;
+
+        let camera_desc = desc.camera.get_ref();
+        let look_from = vec3(camera_desc.look_from);
+        let look_at = vec3(camera_desc.look_at);
+        let camera = Camera::new(
+            &look_from,
+            &look_at,
+            &vec3(camera_desc.up),
+            camera_desc.vfov,
+            settings.width as f32 / settings.height as f32,
+            camera_desc.aperture,
+            camera_desc
+                .focus_distance
+                .unwrap_or_else(|| (look_from - look_at).length()),
+            camera_desc.time[0],
+            camera_desc.time[1],
+        );
+
+        let mut world = World::default();
+        let mut lights = World::default();
+        let mut has_lights = false;
+        for object_desc in desc.objects.iter() {
+            let object = self
+                .object(object_desc.get_ref())
+                .map_err(|e| self.error(object_desc.span().start, e))?;
+            if object_desc.get_ref().light {
+                lights.add(object.clone());
+                has_lights = true;
+            }
+            world.add(object);
+        }
+
+        let world: Box<dyn Hittable + Send + Sync> = if settings.bvh {
+            Box::new(BVH::from_world(world, camera.time0, camera.time1))
+        } else {
+            Box::new(world)
+        };
+        Ok(Scene {
+            world,
+            lights: if has_lights { Some(lights) } else { None },
+            camera,
+            settings,
+        })
+    }
+
+    fn object(&mut self, desc: &'a ObjectDesc) -> Result<SceneObject, String> {
+        let material = match (&desc.material, &desc.medium) {
+            (Some(material), _) | (None, Some(MediumDesc { material, .. })) => {
+                self.material(material)?
+            }
+            (None, None) => return Err("object is missing a material".to_string()),
+        };
+        let mut shapes: Vec<SceneObject> = Vec::new();
+        if let Some(sphere) = &desc.sphere {
+            shapes.push(Arc::new(Sphere::new(
+                vec3(sphere.center),
+                sphere.radius,
+                material.clone(),
+            )));
+        }
+        if let Some(sphere) = &desc.moving_sphere {
+            shapes.push(Arc::new(MovingSphere::new(
+                vec3(sphere.center0),
+                vec3(sphere.center1),
+                sphere.time0,
+                sphere.time1,
+                sphere.radius,
+                material.clone(),
+            )));
+        }
+        if let Some(rect) = &desc.xy_rect {
+            let (x, y) = (
+                rect.range("xy_rect", "x", rect.x)?,
+                rect.range("xy_rect", "y", rect.y)?,
+            );
+            shapes.push(Arc::new(XYRect::new(
+                x[0],
+                x[1],
+                y[0],
+                y[1],
+                rect.k,
+                material.clone(),
+            )));
+        }
+        if let Some(rect) = &desc.xz_rect {
+            let (x, z) = (
+                rect.range("xz_rect", "x", rect.x)?,
+                rect.range("xz_rect", "z", rect.z)?,
+            );
+            shapes.push(Arc::new(XZRect::new(
+                x[0],
+                x[1],
+                z[0],
+                z[1],
+                rect.k,
+                material.clone(),
+            )));
+        }
+        if let Some(rect) = &desc.yz_rect {
+            let (y, z) = (
+                rect.range("yz_rect", "y", rect.y)?,
+                rect.range("yz_rect", "z", rect.z)?,
+            );
+            shapes.push(Arc::new(YZRect::new(
+                y[0],
+                y[1],
+                z[0],
+                z[1],
+                rect.k,
+                material.clone(),
+            )));
+        }
+        if let Some(box3d) = &desc.box3d {
+            shapes.push(Arc::new(Box3d::new(
+                vec3(box3d.min),
+                vec3(box3d.max),
+                material,
+            )));
+        }
+        if shapes.len() != 1 {
+            return Err(format!(
+                "object needs exactly one shape (sphere, moving_sphere, xy_rect, xz_rect, \
+                 yz_rect or box), found {}",
+                shapes.len()
+            ));
+        }
+
+        let mut object = shapes.pop().unwrap();
+        for transform in desc.transforms.iter() {
+            object = match transform {
+                TransformDesc::Translate(offset) => Arc::new(Translate::new(object, vec3(*offset))),
+                TransformDesc::RotateY(angle) => Arc::new(Rotate::new(object, *angle)),
+                TransformDesc::FlipFace => Arc::new(FlipFace::new(object)),
+            };
+        }
+        if let Some(medium) = &desc.medium {
+            let material = self.material(&medium.material)?;
+            object = Arc::new(ConstantMedium::new(object, medium.density, material));
+        }
+        Ok(object)
+    }
+
+    fn material(&mut self, name: &'a str) -> Result<SceneMaterial, String> {
+        if let Some(material) = self.materials.get(name) {
+            return Ok(material.clone());
+        }
+        let desc = self
+            .desc
+            .materials
+            .get(name)
+            .ok_or_else(|| format!("unknown material `{}`", name))?;
+        let material: SceneMaterial = match desc.get_ref() {
+            MaterialDesc::Lambertian { albedo } => Arc::new(Lambertian::new(
+                self.texture_ref(albedo, desc.span().start)?,
+            )),
+            MaterialDesc::Metal { albedo, fuzz } => Arc::new(Metal::new(
+                self.texture_ref(albedo, desc.span().start)?,
+                *fuzz,
+            )),
+            MaterialDesc::Dielectric {
+                index_of_refraction,
+            } => Arc::new(Dielectric::new(*index_of_refraction)),
+            MaterialDesc::DiffuseLight { emit } => Arc::new(DiffuseLight::new(
+                self.texture_ref(emit, desc.span().start)?,
+            )),
+            MaterialDesc::Isotropic { albedo } => {
+                Arc::new(Isotropic::new(self.texture_ref(albedo, desc.span().start)?))
+            }
+        };
+        self.materials.insert(name, material.clone());
+        Ok(material)
+    }
+
+    /// Resolves a texture reference made at byte offset `position` of the source.
+    fn texture_ref(
+        &mut self,
+        texture: &'a TextureRef,
+        position: usize,
+    ) -> Result<SceneTexture, String> {
+        self.resolve_texture(texture, &mut HashSet::new())
+            .map_err(|e| self.error(position, e))
+    }
+
+    fn resolve_texture(
+        &mut self,
+        texture: &'a TextureRef,
+        visiting: &mut HashSet<&'a str>,
+    ) -> Result<SceneTexture, String> {
+        match texture {
+            TextureRef::Color(color) => Ok(Arc::new(SolidTexture::from_color(vec3(*color)))),
+            TextureRef::Name(name) => self.texture(name, visiting),
+        }
+    }
+
+    /// Builds the texture `name`, `visiting` holds the textures currently being built
+    /// to detect checker textures containing themselves.
+    fn texture(
+        &mut self,
+        name: &'a str,
+        visiting: &mut HashSet<&'a str>,
+    ) -> Result<SceneTexture, String> {
+        if let Some(texture) = self.textures.get(name) {
+            return Ok(texture.clone());
+        }
+        let desc = self
+            .desc
+            .textures
+            .get(name)
+            .ok_or_else(|| format!("unknown texture `{}`", name))?;
+        if !visiting.insert(name) {
+            return Err(format!("texture `{}` references itself", name));
+        }
+        let texture: SceneTexture = match desc.get_ref() {
+            TextureDesc::Solid { color } => Arc::new(SolidTexture::from_color(vec3(*color))),
+            TextureDesc::Checker { odd, even } => {
+                let odd = self
+                    .resolve_texture(odd, visiting)
+                    .map_err(|e| self.error(desc.span().start, e))?;
+                let even = self
+                    .resolve_texture(even, visiting)
+                    .map_err(|e| self.error(desc.span().start, e))?;
+                Arc::new(CheckerTexture::from_textures(odd, even))
+            }
+            TextureDesc::Noise { scale } => {
+                // every noise texture gets its own pattern, independent of the build order
+                let seed = name
+                    .bytes()
+                    .fold(self.seed, |seed, byte| hash(&[seed, byte as u64]));
+                Arc::new(NoiseTexture::new(*scale, seed))
+            }
+            TextureDesc::Image { path } => {
+                let path: PathBuf = self.base_dir.join(path);
+                Arc::new(ImageTexture::new(&path.to_string_lossy()).map_err(|e| {
+                    self.error(desc.span().start, format!("{}: {}", path.display(), e))
+                })?)
+            }
+        };
+        visiting.remove(name);
+        self.textures.insert(name, texture.clone());
+        Ok(texture)
+    }
+
+    /// Prefixes `message` with the line of the byte offset `position`, messages that already
+    /// start with a line number are returned unchanged.
+    fn error(&self, position: usize, message: String) -> String {
+        if message.starts_with("line ") {
+            return message;
+        }
+        let line = self.source[..position.min(self.source.len())]
+            .matches('\n')
+            .count()
+            + 1;
+        format!("line {}: {}", line, message)
+    }
+}
+
+impl RectDesc {
+    fn range(&self, shape: &str, axis: &str, range: Option<[f32; 2]>) -> Result<[f32; 2], String> {
+        range.ok_or_else(|| format!("{} is missing the `{}` range", shape, axis))
+    }
+}
+
+fn vec3(value: [f32; 3]) -> Vec3 {
+    Point3::new(value[0], value[1], value[2])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CAMERA: &str = "[camera]
+look_from = [0.0, 0.0, 0.0]
+look_at = [0.0, 0.0, -1.0]
+vfov = 40.0
+";
+
+    fn parse(source: &str) -> Result<Scene, String> {
+        Scene::parse(source, Path::new("."))
+    }
+
+    #[test]
+    fn scene_cornell_box() {
+        let scene = parse(include_str!("../scenes/cornell_box.toml")).unwrap();
+        assert_eq!(scene.settings.width, 600);
+        assert_eq!(scene.settings.samples_per_pixel, 64);
+        assert!(scene.lights.is_some());
+        let aabb = scene.world.bounding_box(0.0, 1.0);
+        assert_eq!(aabb.maximum.x, 555.0);
+    }
+
+    #[test]
+    fn scene_errors_have_line_numbers() {
+        let unknown_material = format!(
+            "{}
+[[objects]]
+sphere = {{ center = [0.0, 0.0, -1.0], radius = 0.5 }}
+material = \"red\"
+",
+            CAMERA
+        );
+        assert_eq!(
+            parse(&unknown_material).err().unwrap(),
+            "line 6: unknown material `red`"
+        );
+
+        let two_shapes = format!(
+            "{}
+[materials.red]
+lambertian = {{ albedo = [0.5, 0.5, 0.5] }}
+
+[[objects]]
+sphere = {{ center = [0.0, 0.0, -1.0], radius = 0.5 }}
+box = {{ min = [0.0, 0.0, 0.0], max = [1.0, 1.0, 1.0] }}
+material = \"red\"
+",
+            CAMERA
+        );
+        assert!(parse(&two_shapes)
+            .err()
+            .unwrap()
+            .starts_with("line 9: object needs exactly one shape"));
+
+        let bad_vector = format!(
+            "{}
+[[objects]]
+sphere = {{ center = [0.0, 0.0], radius = 0.5 }}
+",
+            CAMERA
+        );
+        assert!(parse(&bad_vector)
+            .err()
+            .unwrap()
+            .contains("line 7, column 21"));
+    }
+
+    #[test]
+    fn scene_noise_is_reproducible() {
+        let source = format!(
+            "{}
+[textures.marble]
+noise = {{ scale = 4.0 }}
+
+[materials.marble]
+lambertian = {{ albedo = \"marble\" }}
+
+[[objects]]
+sphere = {{ center = [0.0, 0.0, -1.0], radius = 0.5 }}
+material = \"marble\"
+",
+            CAMERA
+        );
+        let render = |seed: u64| {
+            let scene = Scene::parse_with(&source, Path::new("."), |settings| {
+                settings.width = 16;
+                settings.height = 16;
+                settings.samples_per_pixel = 2;
+                settings.background = Color::new(1.0, 1.0, 1.0);
+                settings.seed = seed;
+            })
+            .unwrap();
+            let mut renderer = scene.renderer().unwrap();
+            scene.render(&mut renderer).unwrap();
+            renderer
+                .hdr_buffer()
+                .iter()
+                .map(|c| [c.x.to_bits(), c.y.to_bits(), c.z.to_bits()])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(render(3), render(3));
+        assert_ne!(render(3), render(4));
+    }
+}
@@ -1,9 +1,27 @@
+use std::sync::Arc;
+
+use crate::hittable::HitRecord;
 use crate::perlin::Perlin;
 use crate::vec3::{Color, Point3};
 use image::GenericImageView;
 
 pub trait Texture {
     fn color(&self, u: f32, v: f32, point: &Point3) -> Color;
+
+    /// Color at a hit, for textures that also need the hit primitive.
+    fn hit_color(&self, hit: &HitRecord) -> Color {
+        self.color(hit.u, hit.v, &hit.point)
+    }
+}
+
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn color(&self, u: f32, v: f32, point: &Point3) -> Color {
+        (**self).color(u, v, point)
+    }
+
+    fn hit_color(&self, hit: &HitRecord) -> Color {
+        (**self).hit_color(hit)
+    }
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -48,15 +66,28 @@ impl CheckerTexture<SolidTexture, SolidTexture> {
     }
 }
 
+impl<O: Texture, E: Texture> CheckerTexture<O, E> {
+    fn is_odd(point: &Point3) -> bool {
+        (10.0 * point.x).sin() * (10.0 * point.y).sin() * (10.0 * point.z).sin() < 0.0
+    }
+}
+
 impl<O: Texture, E: Texture> Texture for CheckerTexture<O, E> {
     fn color(&self, u: f32, v: f32, point: &Point3) -> Color {
-        let sines = (10.0 * point.x).sin() * (10.0 * point.y).sin() * (10.0 * point.z).sin();
-        if sines < 0.0 {
+        if Self::is_odd(point) {
             self.odd.color(u, v, point)
         } else {
             self.even.color(u, v, point)
         }
     }
+
+    fn hit_color(&self, hit: &HitRecord) -> Color {
+        if Self::is_odd(&hit.point) {
+            self.odd.hit_color(hit)
+        } else {
+            self.even.hit_color(hit)
+        }
+    }
 }
 
 pub struct NoiseTexture {
@@ -65,9 +96,10 @@ pub struct NoiseTexture {
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f32) -> Self {
+    /// Marble-like noise, the same `seed` always gives the same pattern.
+    pub fn new(scale: f32, seed: u64) -> Self {
         Self {
-            noise: Perlin::default(),
+            noise: Perlin::new(seed),
             scale,
         }
     }
@@ -81,21 +113,92 @@ impl Texture for NoiseTexture {
     }
 }
 
+/// Per-vertex colors of a `TriangleMesh`, shared with the mesh (see
+/// `TriangleMesh::vertex_color_texture`). The colors of the hit triangle are interpolated with
+/// the barycentric coordinates `(u, v)` that a mesh without UVs reports. White where the
+/// triangle is unknown, and for the default texture without colors.
+#[derive(Debug, Clone, Default)]
+pub struct VertexColorTexture {
+    colors: Arc<[Color]>,
+    indices: Arc<[[u32; 3]]>,
+}
+
+impl VertexColorTexture {
+    /// `colors` holds one color per vertex, `indices` the vertices of every triangle.
+    pub fn new(colors: Arc<[Color]>, indices: Arc<[[u32; 3]]>) -> Self {
+        Self { colors, indices }
+    }
+}
+
+impl Texture for VertexColorTexture {
+    fn color(&self, _: f32, _: f32, _: &Point3) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
+    fn hit_color(&self, hit: &HitRecord) -> Color {
+        let vertices = match self.indices.get(hit.primitive as usize) {
+            Some(vertices) if !self.colors.is_empty() => vertices,
+            _ => return Color::new(1.0, 1.0, 1.0),
+        };
+        let [c0, c1, c2] = vertices.map(|vertex| self.colors[vertex as usize]);
+        (1.0 - hit.u - hit.v) * c0 + hit.u * c1 + hit.v * c2
+    }
+}
+
 pub struct ImageTexture {
-    data: Vec<u8>,
+    data: ImageData,
     width: u32,
     height: u32,
 }
 
+enum ImageData {
+    Rgb8(Vec<u8>),
+    Linear(Vec<Color>),
+}
+
 impl ImageTexture {
     pub fn new(path: &str) -> Result<Self, image::error::ImageError> {
         let img = image::open(path)?;
         Ok(Self {
-            data: Vec::from(img.as_bytes()),
+            data: ImageData::Rgb8(Vec::from(img.as_bytes())),
             width: img.width(),
             height: img.height(),
         })
     }
+
+    /// Creates a texture from 8 bit RGB pixels stored row by row, starting at the top.
+    pub fn from_rgb8(width: u32, height: u32, data: Vec<u8>) -> Result<Self, String> {
+        if width == 0 || height == 0 || data.len() != width as usize * height as usize * 3 {
+            return Err(format!(
+                "{} bytes of pixel data for a {}x{} image",
+                data.len(),
+                width,
+                height
+            ));
+        }
+        Ok(Self {
+            data: ImageData::Rgb8(data),
+            width,
+            height,
+        })
+    }
+
+    /// Creates a texture from linear colors stored row by row, starting at the top.
+    pub fn from_linear(width: u32, height: u32, data: Vec<Color>) -> Result<Self, String> {
+        if width == 0 || height == 0 || data.len() != width as usize * height as usize {
+            return Err(format!(
+                "{} pixels for a {}x{} image",
+                data.len(),
+                width,
+                height
+            ));
+        }
+        Ok(Self {
+            data: ImageData::Linear(data),
+            width,
+            height,
+        })
+    }
 }
 
 impl Texture for ImageTexture {
@@ -111,12 +214,17 @@ impl Texture for ImageTexture {
             j = self.height - 1;
         }
 
-        const COLOR_SCALE: f32 = 1.0 / 255.0;
-        let pixel = j * self.width * 3 + i * 3;
-        Color::new(
-            COLOR_SCALE * self.data[pixel as usize] as f32,
-            COLOR_SCALE * self.data[pixel as usize + 1] as f32,
-            COLOR_SCALE * self.data[pixel as usize + 2] as f32,
-        )
+        let pixel = (j * self.width + i) as usize;
+        match &self.data {
+            ImageData::Rgb8(data) => {
+                const COLOR_SCALE: f32 = 1.0 / 255.0;
+                Color::new(
+                    COLOR_SCALE * data[pixel * 3] as f32,
+                    COLOR_SCALE * data[pixel * 3 + 1] as f32,
+                    COLOR_SCALE * data[pixel * 3 + 2] as f32,
+                )
+            }
+            ImageData::Linear(data) => data[pixel],
+        }
     }
 }
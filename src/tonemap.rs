@@ -0,0 +1,243 @@
+use crate::vec3::Color;
+
+pub trait ToneMap {
+    /// Maps linear scene radiance into the linear [0, 1] display range.
+    fn map(&self, color: Color) -> Color;
+}
+
+/// Clips every channel to [0, 1].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Clamp;
+
+impl ToneMap for Clamp {
+    fn map(&self, color: Color) -> Color {
+        Color::new(
+            color.x.clamp(0.0, 1.0),
+            color.y.clamp(0.0, 1.0),
+            color.z.clamp(0.0, 1.0),
+        )
+    }
+}
+
+/// Extended Reinhard operator applied to the luminance.
+/// Luminance equal to `white` is mapped to 1.0.
+#[derive(Debug, Copy, Clone)]
+pub struct Reinhard {
+    pub white: f32,
+}
+
+impl Reinhard {
+    pub fn new(white: f32) -> Self {
+        Self { white }
+    }
+}
+
+impl Default for Reinhard {
+    fn default() -> Self {
+        Self {
+            white: f32::INFINITY,
+        }
+    }
+}
+
+impl ToneMap for Reinhard {
+    fn map(&self, color: Color) -> Color {
+        let luminance = luminance(&color);
+        if luminance <= 0.0 {
+            return Color::default();
+        }
+        let mapped = luminance * (1.0 + luminance / self.white.powi(2)) / (1.0 + luminance);
+        Clamp.map(color * (mapped / luminance))
+    }
+}
+
+/// Krzysztof Narkowicz's fit of the ACES filmic curve.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Aces;
+
+impl Aces {
+    fn curve(x: f32) -> f32 {
+        const A: f32 = 2.51;
+        const B: f32 = 0.03;
+        const C: f32 = 2.43;
+        const D: f32 = 0.59;
+        const E: f32 = 0.14;
+        ((x * (A * x + B)) / (x * (C * x + D) + E)).clamp(0.0, 1.0)
+    }
+}
+
+impl ToneMap for Aces {
+    fn map(&self, color: Color) -> Color {
+        Color::new(
+            Self::curve(color.x),
+            Self::curve(color.y),
+            Self::curve(color.z),
+        )
+    }
+}
+
+/// John Hable's filmic curve from Uncharted 2.
+/// Values equal to `white` are mapped to 1.0.
+#[derive(Debug, Copy, Clone)]
+pub struct Hable {
+    pub white: f32,
+}
+
+impl Hable {
+    pub fn new(white: f32) -> Self {
+        Self { white }
+    }
+
+    fn curve(x: f32) -> f32 {
+        const A: f32 = 0.15;
+        const B: f32 = 0.50;
+        const C: f32 = 0.10;
+        const D: f32 = 0.20;
+        const E: f32 = 0.02;
+        const F: f32 = 0.30;
+        ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+    }
+}
+
+impl Default for Hable {
+    fn default() -> Self {
+        Self { white: 11.2 }
+    }
+}
+
+impl ToneMap for Hable {
+    fn map(&self, color: Color) -> Color {
+        let scale = 1.0 / Self::curve(self.white);
+        Clamp.map(Color::new(
+            Self::curve(color.x) * scale,
+            Self::curve(color.y) * scale,
+            Self::curve(color.z) * scale,
+        ))
+    }
+}
+
+/// Converts linear radiance into 8-bit sRGB display values:
+/// exposure, then tone mapping, then the sRGB transfer function.
+pub struct DisplayTransform {
+    /// Exposure in stops, the radiance is multiplied by `2^exposure`.
+    pub exposure: f32,
+    pub tone_map: Box<dyn ToneMap + Send + Sync>,
+}
+
+impl DisplayTransform {
+    pub fn new<T: ToneMap + Send + Sync + 'static>(exposure: f32, tone_map: T) -> Self {
+        Self {
+            exposure,
+            tone_map: Box::new(tone_map),
+        }
+    }
+
+    pub fn apply(&self, color: &Color) -> [u8; 3] {
+        let color = self.tone_map.map(*color * 2.0_f32.powf(self.exposure));
+        [
+            Self::encode(color.x),
+            Self::encode(color.y),
+            Self::encode(color.z),
+        ]
+    }
+
+    #[inline]
+    fn encode(value: f32) -> u8 {
+        (255.0 * srgb_oetf(value.clamp(0.0, 1.0)) + 0.5) as u8
+    }
+}
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        Self::new(0.0, Clamp)
+    }
+}
+
+/// sRGB opto-electronic transfer function for values in [0, 1].
+pub fn srgb_oetf(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// sRGB electro-optical transfer function, the inverse of [`srgb_oetf`].
+pub fn srgb_eotf(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Relative luminance of a linear Rec.709 color.
+pub fn luminance(color: &Color) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gray(value: f32) -> Color {
+        Color::new(value, value, value)
+    }
+
+    #[test]
+    fn srgb_oetf_values() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+        // linear segment near black
+        assert!((srgb_oetf(0.001) - 0.01292).abs() < 1e-7);
+        assert!((srgb_oetf(0.0031308) - 12.92 * 0.0031308).abs() < 1e-7);
+        // the two segments meet at the threshold
+        assert!((srgb_oetf(0.0031309) - srgb_oetf(0.0031308)).abs() < 1e-5);
+        assert!((srgb_oetf(0.5) - 0.735357).abs() < 1e-5);
+        for &value in &[0.0, 0.001, 0.0031308, 0.2, 0.5, 1.0] {
+            assert!((srgb_eotf(srgb_oetf(value)) - value).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn tone_maps_monotonic() {
+        let tone_maps: [(&str, Box<dyn ToneMap>); 5] = [
+            ("clamp", Box::new(Clamp)),
+            ("reinhard", Box::new(Reinhard::default())),
+            ("reinhard white", Box::new(Reinhard::new(4.0))),
+            ("aces", Box::new(Aces)),
+            ("hable", Box::new(Hable::default())),
+        ];
+        for (name, tone_map) in tone_maps.iter() {
+            assert!(tone_map.map(gray(0.0)).x.abs() < 1e-6, "{}", name);
+            let mut last = 0.0;
+            for i in 1..=1000 {
+                let mapped = tone_map.map(gray(i as f32 * 0.02)).x;
+                assert!(mapped >= last && mapped <= 1.0, "{} at {}", name, i);
+                last = mapped;
+            }
+        }
+    }
+
+    #[test]
+    fn tone_maps_white_point() {
+        assert!((Reinhard::new(4.0).map(gray(4.0)).x - 1.0).abs() < 1e-6);
+        assert!((Reinhard::default().map(gray(1.0)).x - 0.5).abs() < 1e-6);
+        assert!((Hable::default().map(gray(11.2)).x - 1.0).abs() < 1e-6);
+        assert_eq!(Aces.map(gray(100.0)).x, 1.0);
+        assert_eq!(Clamp.map(Color::new(-1.0, 0.25, 2.0)).y, 0.25);
+    }
+
+    #[test]
+    fn display_transform_encode() {
+        let display = DisplayTransform::default();
+        assert_eq!(display.apply(&gray(0.0)), [0, 0, 0]);
+        assert_eq!(display.apply(&gray(1.0)), [255, 255, 255]);
+        assert_eq!(display.apply(&gray(0.5)), [188, 188, 188]);
+        // one stop of exposure doubles the radiance
+        assert_eq!(
+            DisplayTransform::new(1.0, Clamp).apply(&gray(0.5)),
+            [255; 3]
+        );
+    }
+}
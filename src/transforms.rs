@@ -1,3 +1,6 @@
+use std::sync::Arc;
+
+use crate::sampler::{ray_hash_1d, Sampler};
 use crate::{HitRecord, Hittable, Material, Point3, Ray, Vec3, AABB};
 
 pub struct Translate<T: Hittable> {
@@ -12,28 +15,35 @@ impl<T: Hittable> Translate<T> {
 }
 
 impl<T: Hittable> Hittable for Translate<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let moved_ray = Ray::new(ray.origin - self.offset, ray.direction, ray.time);
-        return if let Some(mut hit) = self.object.hit(&moved_ray, t_min, t_max) {
+        if let Some(mut hit) = self.object.hit(&moved_ray, t_min, t_max) {
             hit.point += self.offset;
             hit.front_face = moved_ray.direction.dot(&hit.normal) < 0.0;
             Some(hit)
         } else {
             None
-        };
+        }
     }
 
-    fn bounding_box(&self) -> AABB {
-        let aabb = self.object.bounding_box();
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        let aabb = self.object.bounding_box(time0, time1);
         AABB::new(aabb.minimum + self.offset, aabb.maximum + self.offset)
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        self.object.pdf_value(&(*origin - self.offset), direction)
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.object.random(&(*origin - self.offset), sampler)
+    }
 }
 
 pub struct Rotate<T: Hittable> {
     pub object: T,
     pub sin_theta: f32,
     pub cos_theta: f32,
-    pub aabb: AABB,
 }
 
 impl<T: Hittable> Rotate<T> {
@@ -41,66 +51,44 @@ impl<T: Hittable> Rotate<T> {
         let radians = angle.to_radians();
         let sin = radians.sin();
         let cos = radians.cos();
-        let aabb = object.bounding_box();
-
-        let mut min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
-        let mut max = Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
-
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
-                    let x = i as f32 * aabb.maximum.x + (1.0 - i as f32) * aabb.minimum.x;
-                    let y = j as f32 * aabb.maximum.y + (1.0 - j as f32) * aabb.minimum.y;
-                    let z = k as f32 * aabb.maximum.z + (1.0 - k as f32) * aabb.minimum.z;
-
-                    let newx = cos * x + sin * z;
-                    let newz = -sin * x + cos * z;
-
-                    min.x = min.x.min(newx);
-                    min.y = min.y.min(y);
-                    min.z = min.z.min(newz);
-
-                    max.x = max.x.max(newx);
-                    max.y = max.y.max(y);
-                    max.z = max.z.max(newz);
-                }
-            }
-        }
-        let aabb = AABB::new(min, max);
 
         Self {
             object,
             sin_theta: sin,
             cos_theta: cos,
-            aabb,
         }
     }
-}
-
-impl<T: Hittable> Hittable for Rotate<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut origin = ray.origin;
-        let mut direction = ray.direction;
 
-        origin.x = self.cos_theta * ray.origin.x - self.sin_theta * ray.origin.z;
-        origin.z = self.sin_theta * ray.origin.x + self.cos_theta * ray.origin.z;
+    /// Rotates `v` from world space into the space of the object.
+    fn to_object(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.cos_theta * v.x - self.sin_theta * v.z,
+            v.y,
+            self.sin_theta * v.x + self.cos_theta * v.z,
+        )
+    }
 
-        direction.x = self.cos_theta * ray.direction.x - self.sin_theta * ray.direction.z;
-        direction.z = self.sin_theta * ray.direction.x + self.cos_theta * ray.direction.z;
+    /// Rotates `v` from the space of the object into world space.
+    fn to_world(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.cos_theta * v.x + self.sin_theta * v.z,
+            v.y,
+            -self.sin_theta * v.x + self.cos_theta * v.z,
+        )
+    }
+}
 
-        let rotated = Ray::new(origin, direction, ray.time);
+impl<T: Hittable> Hittable for Rotate<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let rotated = Ray::new(
+            self.to_object(&ray.origin),
+            self.to_object(&ray.direction),
+            ray.time,
+        );
 
         if let Some(mut hit) = self.object.hit(&rotated, t_min, t_max) {
-            let mut point = hit.point;
-            let mut normal = hit.normal;
-
-            point.x = self.cos_theta * hit.point.x + self.sin_theta * hit.point.z;
-            point.z = -self.sin_theta * hit.point.x + self.cos_theta * hit.point.z;
-
-            normal.x = self.cos_theta * hit.normal.x + self.sin_theta * hit.normal.z;
-            normal.z = -self.sin_theta * hit.normal.x + self.cos_theta * hit.normal.z;
-
-            hit.point = point;
+            let normal = self.to_world(&hit.normal);
+            hit.point = self.to_world(&hit.point);
             hit.front_face = rotated.direction.dot(&normal) < 0.0;
             hit.normal = if hit.front_face { normal } else { -normal };
             Some(hit)
@@ -109,8 +97,40 @@ impl<T: Hittable> Hittable for Rotate<T> {
         }
     }
 
-    fn bounding_box(&self) -> AABB {
-        self.aabb
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        let aabb = self.object.bounding_box(time0, time1);
+
+        let mut min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let x = i as f32 * aabb.maximum.x + (1.0 - i as f32) * aabb.minimum.x;
+                    let y = j as f32 * aabb.maximum.y + (1.0 - j as f32) * aabb.minimum.y;
+                    let z = k as f32 * aabb.maximum.z + (1.0 - k as f32) * aabb.minimum.z;
+                    let corner = self.to_world(&Point3::new(x, y, z));
+
+                    min.x = min.x.min(corner.x);
+                    min.y = min.y.min(corner.y);
+                    min.z = min.z.min(corner.z);
+
+                    max.x = max.x.max(corner.x);
+                    max.y = max.y.max(corner.y);
+                    max.z = max.z.max(corner.z);
+                }
+            }
+        }
+        AABB::new(min, max)
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        self.object
+            .pdf_value(&self.to_object(origin), &self.to_object(direction))
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.to_world(&self.object.random(&self.to_object(origin), sampler))
     }
 }
 
@@ -131,9 +151,9 @@ impl<T: Hittable, M: Material> ConstantMedium<T, M> {
 }
 
 impl<T: Hittable, M: Material> Hittable for ConstantMedium<T, M> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        return if let Some(mut hit1) = self.boundary.hit(ray, f32::NEG_INFINITY, f32::INFINITY) {
-            return if let Some(mut hit2) = self.boundary.hit(ray, hit1.t + 0.0001, f32::INFINITY) {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        if let Some(mut hit1) = self.boundary.hit(ray, f32::NEG_INFINITY, f32::INFINITY) {
+            if let Some(mut hit2) = self.boundary.hit(ray, hit1.t + 0.0001, f32::INFINITY) {
                 if hit1.t < t_min {
                     hit1.t = t_min;
                 }
@@ -151,7 +171,9 @@ impl<T: Hittable, M: Material> Hittable for ConstantMedium<T, M> {
 
                 let ray_length = ray.direction.length();
                 let distance_inside_boundary = (hit2.t - hit1.t) * ray_length;
-                let hit_distance = self.neg_inv_density * rand::random::<f32>().log2();
+                // `hit` has no sampler, the random distance is derived from the ray itself
+                // which keeps it reproducible for a given render seed
+                let hit_distance = self.neg_inv_density * (1.0 - ray_hash_1d(ray)).log2();
 
                 if hit_distance > distance_inside_boundary {
                     return None;
@@ -169,18 +191,20 @@ impl<T: Hittable, M: Material> Hittable for ConstantMedium<T, M> {
                     u: 0.0,
                     v: 0.0,
                     front_face: true,
+                    object_id: 0,
+                    primitive: 0,
                 };
                 Some(record)
             } else {
                 None
-            };
+            }
         } else {
             None
-        };
+        }
     }
 
-    fn bounding_box(&self) -> AABB {
-        self.boundary.bounding_box()
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        self.boundary.bounding_box(time0, time1)
     }
 }
 
@@ -195,16 +219,362 @@ impl<T: Hittable> FlipFace<T> {
 }
 
 impl<T: Hittable> Hittable for FlipFace<T> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        return if let Some(mut hit) = self.object.hit(ray, t_min, t_max) {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        if let Some(mut hit) = self.object.hit(ray, t_min, t_max) {
             hit.front_face = !hit.front_face;
             Some(hit)
         } else {
             None
+        }
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        self.object.bounding_box(time0, time1)
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
+        self.object.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.object.random(origin, sampler)
+    }
+}
+
+/// Affine transform from the space of an object into world space, stored as the rows of a
+/// 3x4 matrix together with its inverse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    matrix: [[f32; 4]; 3],
+    inverse: [[f32; 4]; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    const IDENTITY: [[f32; 4]; 3] = [
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+    ];
+
+    pub fn identity() -> Self {
+        Self {
+            matrix: Self::IDENTITY,
+            inverse: Self::IDENTITY,
+        }
+    }
+
+    /// Transform with the matrix rows `rows`, fails if the matrix is not invertible.
+    pub fn from_rows(rows: [[f32; 4]; 3]) -> Result<Self, String> {
+        let row = |i: usize| Vec3::new(rows[i][0], rows[i][1], rows[i][2]);
+        let determinant = row(0).dot(&row(1).cross(&row(2)));
+        if determinant == 0.0 || !determinant.is_finite() {
+            return Err(format!("transform {:?} is not invertible", rows));
+        }
+        // the columns of the inverse of the linear part are the cross products of its rows
+        let columns = [
+            row(1).cross(&row(2)) / determinant,
+            row(2).cross(&row(0)) / determinant,
+            row(0).cross(&row(1)) / determinant,
+        ];
+        let mut inverse = [[0.0; 4]; 3];
+        for (i, inverse_row) in inverse.iter_mut().enumerate() {
+            for (j, column) in columns.iter().enumerate() {
+                inverse_row[j] = column[i];
+            }
+            inverse_row[3] = -(0..3).map(|j| inverse_row[j] * rows[j][3]).sum::<f32>();
+        }
+        Ok(Self {
+            matrix: rows,
+            inverse,
+        })
+    }
+
+    pub fn translate(offset: Vec3) -> Self {
+        let mut transform = Self::identity();
+        for i in 0..3 {
+            transform.matrix[i][3] = offset[i];
+            transform.inverse[i][3] = -offset[i];
+        }
+        transform
+    }
+
+    /// Counterclockwise rotation by `angle` degrees around `axis`.
+    pub fn rotate(axis: Vec3, angle: f32) -> Self {
+        let rotation = |angle: f32| {
+            let (sin, cos) = angle.to_radians().sin_cos();
+            let a = axis.unit();
+            let t = 1.0 - cos;
+            [
+                [
+                    t * a.x * a.x + cos,
+                    t * a.x * a.y - sin * a.z,
+                    t * a.x * a.z + sin * a.y,
+                    0.0,
+                ],
+                [
+                    t * a.x * a.y + sin * a.z,
+                    t * a.y * a.y + cos,
+                    t * a.y * a.z - sin * a.x,
+                    0.0,
+                ],
+                [
+                    t * a.x * a.z - sin * a.y,
+                    t * a.y * a.z + sin * a.x,
+                    t * a.z * a.z + cos,
+                    0.0,
+                ],
+            ]
+        };
+        Self {
+            matrix: rotation(angle),
+            inverse: rotation(-angle),
+        }
+    }
+
+    /// Scales by the factors along each axis, which must not be zero.
+    pub fn scale(factors: Vec3) -> Self {
+        let mut transform = Self::identity();
+        for i in 0..3 {
+            transform.matrix[i][i] = factors[i];
+            transform.inverse[i][i] = 1.0 / factors[i];
+        }
+        transform
+    }
+
+    /// Transform applying `self` first and `next` to its result.
+    pub fn then(&self, next: &Transform) -> Self {
+        Self {
+            matrix: compose(&self.matrix, &next.matrix),
+            inverse: compose(&next.inverse, &self.inverse),
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn transform_point(&self, p: &Point3) -> Point3 {
+        apply(&self.matrix, p, 1.0)
+    }
+
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        apply(&self.matrix, v, 0.0)
+    }
+
+    /// Transforms a surface normal with the inverse transpose, the result is not normalized.
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.inverse;
+        Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+
+    pub fn inverse_point(&self, p: &Point3) -> Point3 {
+        apply(&self.inverse, p, 1.0)
+    }
+
+    pub fn inverse_vector(&self, v: &Vec3) -> Vec3 {
+        apply(&self.inverse, v, 0.0)
+    }
+
+    /// Box containing `aabb` after the transform.
+    pub fn transform_box(&self, aabb: &AABB) -> AABB {
+        let mut result = AABB::empty();
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 {
+                    aabb.minimum.x
+                } else {
+                    aabb.maximum.x
+                },
+                if i & 2 == 0 {
+                    aabb.minimum.y
+                } else {
+                    aabb.maximum.y
+                },
+                if i & 4 == 0 {
+                    aabb.minimum.z
+                } else {
+                    aabb.maximum.z
+                },
+            );
+            result = result.include(&self.transform_point(&corner));
+        }
+        result
+    }
+}
+
+fn apply(m: &[[f32; 4]; 3], v: &Vec3, w: f32) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z + m[0][3] * w,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z + m[1][3] * w,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z + m[2][3] * w,
+    )
+}
+
+/// Matrix applying `first` and then `second`.
+fn compose(first: &[[f32; 4]; 3], second: &[[f32; 4]; 3]) -> [[f32; 4]; 3] {
+    let mut result = [[0.0; 4]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| second[i][k] * first[k][j]).sum();
+        }
+        row[3] += second[i][3];
+    }
+    result
+}
+
+/// Object shared between instances, placed in the scene by `transform`. Instancing a `BVH`
+/// of a complex object many times only costs the memory of one copy of its geometry, a `BVH`
+/// over a `World` of instances is then the top level of a two-level hierarchy.
+///
+/// Instances do not sample their object and cannot be used as lights.
+pub struct Instance<T: Hittable> {
+    pub object: Arc<T>,
+    pub transform: Transform,
+}
+
+impl<T: Hittable> Instance<T> {
+    pub fn new(object: Arc<T>, transform: Transform) -> Self {
+        Self { object, transform }
+    }
+}
+
+impl<T: Hittable> Hittable for Instance<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        // the direction is not normalized, so distances along the ray stay the same
+        let local = Ray::new(
+            self.transform.inverse_point(&ray.origin),
+            self.transform.inverse_vector(&ray.direction),
+            ray.time,
+        );
+        let mut hit = self.object.hit(&local, t_min, t_max)?;
+        hit.point = self.transform.transform_point(&hit.point);
+        // the inverse transpose keeps the normal on the side of the ray
+        hit.normal = self.transform.transform_normal(&hit.normal).unit();
+        Some(hit)
+    }
+
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        self.transform
+            .transform_box(&self.object.bounding_box(time0, time1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::objects::{Box3d, Sphere};
+    use crate::texture::SolidTexture;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_near(a: &Vec3, b: &Vec3) {
+        assert!((*a - *b).length() < 1e-3, "{:?} != {:?}", a, b);
+    }
+
+    fn random_ray(rng: &mut StdRng) -> Ray {
+        let mut point = || {
+            Point3::new(
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+                rng.gen_range(-10.0..10.0),
+            )
         };
+        let origin = 2.0 * point();
+        Ray::new(origin, point() - origin, 0.0)
+    }
+
+    #[test]
+    fn transform_inverse() {
+        let transform = Transform::scale(Vec3::new(2.0, -1.0, 0.5))
+            .then(&Transform::rotate(Vec3::new(1.0, 2.0, 3.0), 40.0))
+            .then(&Transform::translate(Vec3::new(1.0, -2.0, 3.0)));
+        let from_rows = Transform::from_rows(transform.matrix).unwrap();
+        let p = Point3::new(0.3, -4.0, 2.0);
+        assert_near(&transform.inverse_point(&transform.transform_point(&p)), &p);
+        assert_near(&from_rows.inverse_point(&p), &transform.inverse_point(&p));
+        assert_near(&from_rows.inverse_vector(&p), &transform.inverse_vector(&p));
+        assert_near(
+            &transform.inverse().transform_point(&p),
+            &transform.inverse_point(&p),
+        );
+        // same direction as the rotation of Rotate
+        assert_near(
+            &Transform::rotate(Vec3::new(0.0, 1.0, 0.0), 90.0)
+                .transform_vector(&Vec3::new(1.0, 0.0, 0.0)),
+            &Vec3::new(0.0, 0.0, -1.0),
+        );
+        assert!(Transform::from_rows([[1.0, 0.0, 0.0, 0.0]; 3]).is_err());
     }
 
-    fn bounding_box(&self) -> AABB {
-        self.object.bounding_box()
+    #[test]
+    fn instance_matches_geometry() {
+        let material = Lambertian::new(SolidTexture::from_rgb(0.5, 0.5, 0.5));
+        let offset = Vec3::new(1.0, 2.0, -3.0);
+
+        let sphere = Sphere::new(offset, 3.0, material);
+        let scaled = Instance::new(
+            Arc::new(Sphere::new(Point3::default(), 1.0, material)),
+            Transform::scale(Vec3::new(3.0, 3.0, 3.0)).then(&Transform::translate(offset)),
+        );
+        let cube = Box3d::new(Point3::default(), Point3::new(2.0, 3.0, 4.0), material);
+        let rotated = Translate::new(Rotate::new(cube, 30.0), offset);
+        let instance = Instance::new(
+            Arc::new(cube),
+            Transform::rotate(Vec3::new(0.0, 1.0, 0.0), 30.0).then(&Transform::translate(offset)),
+        );
+
+        let pairs: [(&dyn Hittable, &dyn Hittable); 2] =
+            [(&sphere, &scaled), (&rotated, &instance)];
+        for (expected, actual) in pairs.iter() {
+            let aabb = expected.bounding_box(0.0, 1.0);
+            let instance_aabb = actual.bounding_box(0.0, 1.0);
+            assert_near(&aabb.minimum, &instance_aabb.minimum);
+            assert_near(&aabb.maximum, &instance_aabb.maximum);
+
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut hits = 0;
+            for _ in 0..1000 {
+                let ray = random_ray(&mut rng);
+                let expected = expected.hit(&ray, 0.001, f32::INFINITY);
+                let actual = actual.hit(&ray, 0.001, f32::INFINITY);
+                assert_eq!(expected.is_some(), actual.is_some());
+                if let (Some(expected), Some(actual)) = (expected, actual) {
+                    assert!((expected.t - actual.t).abs() < 1e-4);
+                    assert_near(&expected.point, &actual.point);
+                    // Translate and Rotate pick the side of the normal themselves
+                    assert!(expected.normal.dot(&actual.normal).abs() > 0.999);
+                    assert!(actual.normal.dot(&ray.direction) < 0.0);
+                    hits += 1;
+                }
+            }
+            assert!(hits > 10);
+        }
+
+        let inside = Ray::new(offset, Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let hit = scaled.hit(&inside, 0.001, f32::INFINITY).unwrap();
+        assert!(!hit.front_face);
+        assert_near(&hit.normal, &Vec3::new(0.0, -1.0, 0.0));
+        let outside = Ray::new(
+            offset - Vec3::new(0.0, 10.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+        );
+        let hit = scaled.hit(&outside, 0.001, f32::INFINITY).unwrap();
+        assert!(hit.front_face);
+        assert!((hit.t - 7.0).abs() < 1e-4);
     }
 }
@@ -1,7 +1,9 @@
-use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Neg, Sub};
+use std::ops::{Add, AddAssign, Div, Index, Mul, MulAssign, Neg, Sub};
 
 use rand::distributions::Distribution;
 
+use crate::sampler::Sampler;
+
 pub type Point3 = Vec3;
 pub type Color = Vec3;
 
@@ -17,53 +19,59 @@ impl Vec3 {
         Self { x, y, z }
     }
 
-    pub fn random(min: f32, max: f32) -> Self {
-        let mut rng = rand::thread_rng();
+    pub fn random<R: rand::Rng + ?Sized>(rng: &mut R, min: f32, max: f32) -> Self {
         let uniform = rand::distributions::Uniform::new(min, max);
         Self {
-            x: uniform.sample(&mut rng),
-            y: uniform.sample(&mut rng),
-            z: uniform.sample(&mut rng),
+            x: uniform.sample(rng),
+            y: uniform.sample(rng),
+            z: uniform.sample(rng),
         }
     }
 
-    pub fn random_vec2(min: f32, max: f32) -> Self {
-        let mut rng = rand::thread_rng();
+    pub fn random_vec2<R: rand::Rng + ?Sized>(rng: &mut R, min: f32, max: f32) -> Self {
         let uniform = rand::distributions::Uniform::new(min, max);
         Self {
-            x: uniform.sample(&mut rng),
-            y: uniform.sample(&mut rng),
+            x: uniform.sample(rng),
+            y: uniform.sample(rng),
             z: 0.0,
         }
     }
 
-    pub fn random_unit() -> Self {
-        Self::random(-1.0, 1.0).unit()
+    pub fn random_unit(sampler: &mut dyn Sampler) -> Self {
+        Self::random_in_unit_sphere(sampler).unit()
     }
 
-    pub fn random_in_unit_sphere() -> Self {
-        loop {
-            let point = Vec3::random(-1.0, 1.0);
-            if point.length_squared() < 1.0 {
-                return point;
-            }
-        }
+    pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Self {
+        // uniform direction scaled by a radius with density proportional to r^2,
+        // no rejection so every point uses the same sampler dimensions
+        let (r1, r2) = sampler.get_2d();
+        let z = 1.0 - 2.0 * r1;
+        let r = (1.0 - z.powi(2)).max(0.0).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * r2;
+        let radius = sampler.get_1d().cbrt();
+        radius * Self::new(r * phi.cos(), r * phi.sin(), z)
     }
 
-    pub fn random_in_unit_disk() -> Self {
-        loop {
-            let point = Vec3::random_vec2(-1.0, 1.0);
-            if point.length_squared() < 1.0 {
-                return point;
-            }
+    pub fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Self {
+        // Shirley-Chiu concentric mapping of the unit square onto the disk
+        use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+        let (r1, r2) = sampler.get_2d();
+        let x = 2.0 * r1 - 1.0;
+        let y = 2.0 * r2 - 1.0;
+        if x == 0.0 && y == 0.0 {
+            return Self::default();
         }
+        let (r, theta) = if x.abs() > y.abs() {
+            (x, FRAC_PI_4 * (y / x))
+        } else {
+            (y, FRAC_PI_2 - FRAC_PI_4 * (x / y))
+        };
+        Self::new(r * theta.cos(), r * theta.sin(), 0.0)
     }
 
-    pub fn random_cosine_direction() -> Self {
-        let mut rng = rand::thread_rng();
-        let uniform = rand::distributions::Uniform::<f32>::new(0.0, 1.0);
-        let r1 = uniform.sample(&mut rng);
-        let r2 = uniform.sample(&mut rng);
+    pub fn random_cosine_direction(sampler: &mut dyn Sampler) -> Self {
+        let (r1, r2) = sampler.get_2d();
         let z = (1.0 - r2).sqrt();
 
         let phi = 2.0 * std::f32::consts::PI * r1;
@@ -73,11 +81,8 @@ impl Vec3 {
         Self { x, y, z }
     }
 
-    pub fn random_to_sphere(radius: f32, distance_squared: f32) -> Self {
-        let mut rng = rand::thread_rng();
-        let uniform = rand::distributions::Uniform::<f32>::new(0.0, 1.0);
-        let r1 = uniform.sample(&mut rng);
-        let r2 = uniform.sample(&mut rng);
+    pub fn random_to_sphere(radius: f32, distance_squared: f32, sampler: &mut dyn Sampler) -> Self {
+        let (r1, r2) = sampler.get_2d();
         let z = 1.0 + r2 * ((1.0 - radius.powi(2) / distance_squared).sqrt() - 1.0);
 
         let phi = 2.0 * std::f32::consts::PI * r1;
@@ -129,6 +134,19 @@ impl Vec3 {
     }
 }
 
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 axis {} out of range", axis),
+        }
+    }
+}
+
 impl Neg for Vec3 {
     type Output = Self;
 
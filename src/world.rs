@@ -2,15 +2,15 @@ use std::alloc::Layout;
 use std::any::TypeId;
 use std::collections::HashMap;
 
-use rand::Rng;
-
 use crate::aabb::AABB;
 use crate::blobvec::BlobVec;
+use crate::bvh::Primitives;
 use crate::hittable::{HitRecord, Hittable};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::{HittableVTable, Point3, Vec3};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct WorldIndex {
     type_index: usize,
     object_index: usize,
@@ -18,7 +18,6 @@ pub struct WorldIndex {
 
 #[derive(Default)]
 pub struct World {
-    aabb: AABB,
     types: HashMap<TypeId, usize>,
     data: Vec<(HittableVTable, BlobVec)>,
 }
@@ -34,21 +33,29 @@ impl World {
             data.push((vtable, blob_vec));
             data.len() - 1
         });
-        self.aabb = AABB::surrounding_box(self.aabb, object.bounding_box());
         let (_vtable, blob_vec) = &mut self.data[*index];
         unsafe { blob_vec.add(object) };
     }
 
-    pub fn volumes(&self) -> Vec<(WorldIndex, AABB)> {
+    /// Objects of type `T` in the order they were added, for example to move them.
+    pub fn objects_mut<T: Hittable + 'static>(&mut self) -> &mut [T] {
+        match self.types.get(&TypeId::of::<T>()) {
+            Some(index) => unsafe { self.data[*index].1.as_slice_mut::<T>() },
+            None => &mut [],
+        }
+    }
+
+    /// Boxes of all objects containing them during `[time0, time1]`.
+    pub fn volumes(&self, time0: f32, time1: f32) -> Vec<(WorldIndex, AABB)> {
         let mut volumes = Vec::new();
-        for (_, type_index) in self.types.iter() {
-            let (vtable, blob) = &self.data[*type_index];
+        // in the order the types were added, so the BVH is the same every run
+        for (type_index, (vtable, blob)) in self.data.iter().enumerate() {
             for object_index in 0..blob.len() {
                 let ptr = unsafe { blob.get(object_index) };
-                let aabb = vtable.bounding_box(ptr);
+                let aabb = vtable.bounding_box(ptr, time0, time1);
                 volumes.push((
                     WorldIndex {
-                        type_index: *type_index,
+                        type_index,
                         object_index,
                     },
                     aabb,
@@ -64,24 +71,76 @@ impl World {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-    ) -> Option<HitRecord> {
+    ) -> Option<HitRecord<'_>> {
         let (vtable, blob) = &self.data[index.type_index];
         let ptr = unsafe { blob.get(index.object_index) };
-        vtable.hit(ptr, ray, t_min, t_max)
+        let mut record = vtable.hit(ptr, ray, t_min, t_max)?;
+        record.object_id = self.object_id(index);
+        Some(record)
+    }
+
+    /// Id of the object unique inside this world, ids start from `1` in the order of
+    /// object types being added. Stays the same as long as no objects are added.
+    pub fn object_id(&self, index: &WorldIndex) -> u32 {
+        let preceding = self.data[..index.type_index]
+            .iter()
+            .fold(0, |sum, (_, blob)| sum + blob.len());
+        (preceding + index.object_index + 1) as u32
+    }
+}
+
+impl Primitives for World {
+    type Index = WorldIndex;
+
+    fn volumes(&self, time0: f32, time1: f32) -> Vec<(WorldIndex, AABB)> {
+        World::volumes(self, time0, time1)
+    }
+
+    fn primitive_bounding_box(&self, index: &WorldIndex, time0: f32, time1: f32) -> AABB {
+        let (vtable, blob) = &self.data[index.type_index];
+        vtable.bounding_box(unsafe { blob.get(index.object_index) }, time0, time1)
+    }
+
+    fn hit_primitive(
+        &self,
+        index: &WorldIndex,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<HitRecord<'_>> {
+        self.hit_object(index, ray, t_min, t_max)
+    }
+
+    fn primitive_pdf_value(&self, index: &WorldIndex, origin: &Point3, direction: &Vec3) -> f32 {
+        let (vtable, blob) = &self.data[index.type_index];
+        vtable.pdf_value(unsafe { blob.get(index.object_index) }, origin, direction)
+    }
+
+    fn primitive_random(
+        &self,
+        index: &WorldIndex,
+        origin: &Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let (vtable, blob) = &self.data[index.type_index];
+        vtable.random(unsafe { blob.get(index.object_index) }, origin, sampler)
     }
 }
 
 impl Hittable for World {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let mut last_record = HitRecord::default();
         let mut hit_anything = false;
         let mut closest = t_max;
+        let mut object_id = 0;
         for (vtable, blob) in self.data.iter() {
             for i in 0..blob.len() {
+                object_id += 1;
                 let ptr = unsafe { blob.get(i) };
-                if let Some(record) = vtable.hit(ptr, ray, t_min, closest) {
+                if let Some(mut record) = vtable.hit(ptr, ray, t_min, closest) {
                     hit_anything = true;
                     closest = record.t;
+                    record.object_id = object_id;
                     last_record = record;
                 }
             }
@@ -93,8 +152,12 @@ impl Hittable for World {
         }
     }
 
-    fn bounding_box(&self) -> AABB {
-        self.aabb
+    fn bounding_box(&self, time0: f32, time1: f32) -> AABB {
+        self.volumes(time0, time1)
+            .iter()
+            .fold(AABB::empty(), |aabb, (_, volume)| {
+                AABB::surrounding_box(aabb, *volume)
+            })
     }
 
     fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f32 {
@@ -109,10 +172,11 @@ impl Hittable for World {
         })
     }
 
-    fn random(&self, origin: &Vec3) -> Vec3 {
-        let obj_type = rand::thread_rng().gen_range(0..self.data.len());
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let obj_type =
+            ((sampler.get_1d() * self.data.len() as f32) as usize).min(self.data.len() - 1);
         let (vtable, blob) = &self.data[obj_type];
-        let obj_pos = rand::thread_rng().gen_range(0..blob.len());
-        vtable.random(unsafe { blob.get(obj_pos) }, origin)
+        let obj_pos = ((sampler.get_1d() * blob.len() as f32) as usize).min(blob.len() - 1);
+        vtable.random(unsafe { blob.get(obj_pos) }, origin, sampler)
     }
 }